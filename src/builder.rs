@@ -0,0 +1,601 @@
+use std::{
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use nix::sys::{
+    inotify::{InitFlags, Inotify},
+    resource::{getrlimit, setrlimit, Resource},
+};
+use tokio::sync::oneshot::Sender as OnceSend;
+
+use crate::{
+    error::{AnotifyError, InitError},
+    futures::{DirectoryWatchStream, FileWatchStream, Timestamp},
+    handle::{Handle, OwnedHandle, PreWatch, WatchConfig, WatchType},
+    task::{CreateRetry, Parked, ShutdownSignal, WatcherState},
+};
+
+/// Configuration for a new anotify instance, see [`crate::builder`]
+///
+/// The watcher task is spawned onto the runtime given to [`runtime`][`Builder::runtime`] by
+/// [`build`][`Builder::build`], or onto the current one by
+/// [`build_current`][`Builder::build_current`], so one of them has to be picked:
+///
+/// ```compile_fail
+/// let owner = anotify::builder().build();
+/// ```
+#[derive(Debug)]
+pub struct Builder<R = NoRuntimeSet> {
+    runtime: R,
+    request_buffer: usize,
+    dedicated_thread: bool,
+    name: String,
+    expected_watches: usize,
+    create_retry: Option<CreateRetry>,
+    timestamps: TimestampMode,
+    pre_watches: Vec<PreWatch>,
+    inotify: Option<Inotify>,
+    init_flags: InitFlags,
+    raise_limits: bool,
+}
+
+/// A [`Builder`] which has not been given a runtime, see [`Builder::runtime`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoRuntimeSet;
+
+/// A stream for a watch installed while building, see [`Builder::pre_watch`]
+#[derive(Debug)]
+pub enum PreWatched {
+    File(FileWatchStream),
+    Directory(DirectoryWatchStream),
+}
+
+impl PreWatched {
+    /// The stream, if this was a file watch
+    pub fn into_file(self) -> Option<FileWatchStream> {
+        match self {
+            PreWatched::File(stream) => Some(stream),
+            PreWatched::Directory(_) => None,
+        }
+    }
+
+    /// The stream, if this was a directory watch
+    pub fn into_dir(self) -> Option<DirectoryWatchStream> {
+        match self {
+            PreWatched::Directory(stream) => Some(stream),
+            PreWatched::File(_) => None,
+        }
+    }
+}
+
+/// The watcher task as a future, for callers which drive it themselves, see
+/// [`Builder::build_manual`]
+///
+/// Completes once the task exits, after every [`Handle`] has been dropped or inotify fails.
+#[must_use = "the watcher does nothing unless polled"]
+pub struct WatcherFuture {
+    task: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// Held so that the task only stops once every handle is dropped, as there is no
+    /// [`OwnedHandle`] to shut it down
+    _shutdown: OnceSend<ShutdownSignal>,
+}
+
+impl Future for WatcherFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.task.as_mut().poll(cx)
+    }
+}
+
+impl std::fmt::Debug for WatcherFuture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WatcherFuture").finish_non_exhaustive()
+    }
+}
+
+/// Which clocks to record event [`Timestamp`]s from, see [`Builder::timestamps`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampMode {
+    /// Leave every timestamp as [`Timestamp::None`]
+    #[default]
+    None,
+    /// Record an [`Instant`][`std::time::Instant`]
+    Monotonic,
+    /// Record a [`SystemTime`][`std::time::SystemTime`]
+    Wall,
+    Both,
+}
+
+/// Read from tokio's clock, like every other timer in the watcher task, so that it can be paused
+fn monotonic_now() -> std::time::Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+impl TimestampMode {
+    pub(crate) fn now(self) -> Timestamp {
+        match self {
+            TimestampMode::None => Timestamp::None,
+            TimestampMode::Monotonic => Timestamp::Monotonic(monotonic_now()),
+            TimestampMode::Wall => Timestamp::Wall(std::time::SystemTime::now()),
+            TimestampMode::Both => Timestamp::Both(monotonic_now(), std::time::SystemTime::now()),
+        }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            runtime: NoRuntimeSet,
+            request_buffer: OwnedHandle::DEFAULT_REQUEST_BUFFER,
+            dedicated_thread: false,
+            name: Self::DEFAULT_NAME.into(),
+            expected_watches: 0,
+            create_retry: None,
+            timestamps: TimestampMode::None,
+            pre_watches: Vec::new(),
+            inotify: None,
+            init_flags: InitFlags::IN_NONBLOCK,
+            raise_limits: false,
+        }
+    }
+}
+
+impl<R: Clone> Clone for Builder<R> {
+    /// Clone the configuration, without any instance given to
+    /// [`with_inotify`][`Builder::with_inotify`]
+    ///
+    /// Two tasks on the same fd would each read the other's events and remove the other's
+    /// watches, so the clone creates its own instance instead.
+    fn clone(&self) -> Self {
+        Self {
+            runtime: self.runtime.clone(),
+            request_buffer: self.request_buffer,
+            dedicated_thread: self.dedicated_thread,
+            name: self.name.clone(),
+            expected_watches: self.expected_watches,
+            create_retry: self.create_retry,
+            timestamps: self.timestamps,
+            pre_watches: self.pre_watches.clone(),
+            inotify: None,
+            init_flags: self.init_flags,
+            raise_limits: self.raise_limits,
+        }
+    }
+}
+
+impl Builder {
+    pub const DEFAULT_NAME: &'static str = "anotify";
+
+    /// Spawn the watcher task onto `runtime`, which makes [`build`][`Builder::build`] available
+    ///
+    /// This may be called from outside of any runtime, such as from a plain `main` which keeps
+    /// the runtime around. Ignored when using a [`dedicated_thread`][`Builder::dedicated_thread`].
+    pub fn runtime(self, runtime: tokio::runtime::Handle) -> Builder<tokio::runtime::Handle> {
+        Builder {
+            runtime,
+            request_buffer: self.request_buffer,
+            dedicated_thread: self.dedicated_thread,
+            name: self.name,
+            expected_watches: self.expected_watches,
+            create_retry: self.create_retry,
+            timestamps: self.timestamps,
+            pre_watches: self.pre_watches,
+            inotify: self.inotify,
+            init_flags: self.init_flags,
+            raise_limits: self.raise_limits,
+        }
+    }
+
+    /// Create the inotify instance and launch the watcher task on the current runtime
+    ///
+    /// Must be called from within a tokio runtime, unless using a
+    /// [`dedicated_thread`][`Builder::dedicated_thread`], and fails with
+    /// [`InitError::NoRuntime`] otherwise. Use [`runtime`][`Builder::runtime`] to pick the runtime
+    /// up front instead.
+    pub fn build_current(mut self) -> Result<OwnedHandle, InitError> {
+        self.pre_watches.clear();
+        let (handle, _) = self.launch(None, None)?;
+
+        Ok(handle)
+    }
+}
+
+impl Builder<tokio::runtime::Handle> {
+    /// Create the inotify instance and launch the watcher task on the runtime given to
+    /// [`runtime`][`Builder::runtime`]
+    pub fn build(mut self) -> Result<OwnedHandle, InitError> {
+        self.pre_watches.clear();
+        let runtime = Some(self.runtime.clone());
+        let (handle, _) = self.launch(runtime, None)?;
+
+        Ok(handle)
+    }
+
+    /// Like [`build`][`Builder::build`], but also install every
+    /// [`pre_watch`][`Builder::pre_watch`], returning their streams in the order they were added
+    ///
+    /// Fails if any of them cannot be installed, in which case the watcher task is shut down.
+    pub fn build_watching(self) -> Result<(OwnedHandle, Vec<PreWatched>), AnotifyError> {
+        let runtime = Some(self.runtime.clone());
+        let (handle, watched) = self.launch(runtime, None)?;
+
+        Ok((handle, watched?))
+    }
+
+    /// Like [`build`][`Builder::build`], but also return a future which completes once the
+    /// watcher task is running
+    ///
+    /// Requests sent before then are queued rather than lost, so this is only needed where
+    /// startup has to be deterministic, such as in tests. The future completes early if the task
+    /// exits before it starts running.
+    pub fn build_ready(mut self) -> Result<(OwnedHandle, impl Future<Output = ()>), InitError> {
+        self.pre_watches.clear();
+        let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+        let runtime = Some(self.runtime.clone());
+        let (handle, _) = self.launch(runtime, Some(ready_tx))?;
+
+        Ok((handle, async move {
+            let _ = ready_rx.await;
+        }))
+    }
+}
+
+impl<R> Builder<R> {
+    /// Set the number of watch requests which may be queued for the watcher task
+    pub fn request_buffer(mut self, size: usize) -> Self {
+        self.request_buffer = size;
+        self
+    }
+
+    /// Set weather the watcher task should run on its own thread and runtime
+    ///
+    /// By default the task is spawned onto the runtime it is built for, where a burst of events
+    /// can add latency to other tasks. A dedicated thread keeps that work isolated, while the
+    /// returned handle can still be used from any runtime.
+    pub fn dedicated_thread(mut self, set: bool) -> Self {
+        self.dedicated_thread = set;
+        self
+    }
+
+    /// Set the name used for the watcher task's tracing span, task, and dedicated thread
+    ///
+    /// Useful for telling instances apart when a process has several of them.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set how many watches to reserve space for up front
+    ///
+    /// Avoids repeatedly growing the watcher task's tables when many watches are registered at
+    /// once, such as at startup.
+    pub fn expected_watches(mut self, count: usize) -> Self {
+        self.expected_watches = count;
+        self
+    }
+
+    /// Retry installing a watch up to `attempts` more times when a system limit stops it, such as
+    /// the number of open files or inotify watches
+    ///
+    /// These limits can clear as other watches and files are closed. The first retry is after
+    /// `base_delay`, which doubles for each one after that. Retries are scheduled by the watcher
+    /// task, so it carries on handling other requests in the meantime, but dispatching the watch
+    /// only completes once it is installed or the last attempt fails.
+    pub fn create_retry(mut self, attempts: u32, base_delay: Duration) -> Self {
+        self.create_retry = Some(CreateRetry {
+            attempts,
+            base_delay,
+        });
+        self
+    }
+
+    /// Set which clocks each event's [`timestamp`][`crate::futures::DirectoryWatchEvent::timestamp`]
+    /// is taken from, [`TimestampMode::None`] by default
+    ///
+    /// Wall clock time suits logging, while monotonic time suits measuring latency, so only the
+    /// ones which are needed have to be read. Monotonic time is read from tokio's clock, as are
+    /// the deadlines for features such as [`settle`][`crate::handle::WatchRequest::settle`], so
+    /// they can all be driven with [`tokio::time::pause`] in tests.
+    pub fn timestamps(mut self, mode: TimestampMode) -> Self {
+        self.timestamps = mode;
+        self
+    }
+
+    /// Add a stream watch on `path` which is installed before the watcher task starts
+    ///
+    /// The streams are returned by [`build_watching`][`Builder::build_watching`], with every
+    /// watch already installed, so no events can be missed between building and watching.
+    /// Other build methods ignore these.
+    pub fn pre_watch<T: WatchType>(
+        mut self,
+        path: impl Into<PathBuf>,
+        config: &WatchConfig<T>,
+    ) -> Self {
+        self.pre_watches.push(config.pre_watch(path.into()));
+        self
+    }
+
+    /// Set weather to raise the process's soft limit on open files to its hard limit when
+    /// building, and log the system's inotify watch limit
+    ///
+    /// Suits daemons which need many watches, as each stream holds no files of its own but the
+    /// default soft limit is often far below what the process is allowed. Failing to raise it is
+    /// only logged. The inotify limits can only be raised by an administrator, so are just
+    /// logged to show what needs tuning.
+    pub fn raise_limits(mut self, set: bool) -> Self {
+        self.raise_limits = set;
+        self
+    }
+
+    /// Use an inotify instance which has already been created, such as one with other
+    /// [`InitFlags`][`nix::sys::inotify::InitFlags`], rather than creating a new one
+    ///
+    /// It is switched to non-blocking mode when building, and from then on belongs to the
+    /// watcher task, which closes it once it exits. Events already queued on it are dropped
+    /// unless they are for a watch the task installs, so it should not have any watches of its
+    /// own. Clones of this builder do not carry it.
+    pub fn with_inotify(mut self, inotify: Inotify) -> Self {
+        self.inotify = Some(inotify);
+        self
+    }
+
+    /// Set the flags the inotify instance is created with, by default only `IN_NONBLOCK`
+    ///
+    /// The watcher task only reads from inotify once tokio reports it readable, which relies on
+    /// those reads never blocking, so building fails with [`InitError::Blocking`] unless the flags
+    /// include `IN_NONBLOCK`. Ignored when using
+    /// [`with_inotify`][`Builder::with_inotify`].
+    pub fn init_flags(mut self, flags: InitFlags) -> Self {
+        self.init_flags = flags;
+        self
+    }
+
+    /// Create the inotify instance, returning the watcher task as a future to drive rather than
+    /// spawning it
+    ///
+    /// This suits embedding the watcher in an event loop which polls its own futures. The
+    /// inotify fd and the watcher's timers are still registered with tokio, so this must be
+    /// called, and the future polled, from within the context of a tokio runtime whose IO and
+    /// time drivers keep running, such as a multi-thread runtime which has been
+    /// [entered][`tokio::runtime::Runtime::enter`]. Requests are only handled while the future is
+    /// being polled.
+    ///
+    /// Dropping the future stops the watcher for good, like
+    /// [`abort_handle`][`OwnedHandle::abort_handle`], and every watch then ends. It must not be
+    /// dropped part way through to be polled again later, as there is nothing to resume. Ignores
+    /// [`dedicated_thread`][`Builder::dedicated_thread`] and
+    /// [`pre_watch`][`Builder::pre_watch`].
+    pub fn build_manual(mut self) -> Result<(Handle, WatcherFuture), InitError> {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return Err(InitError::NoRuntime);
+        }
+        if self.raise_limits {
+            raise_limits();
+        }
+        let inotify = self.inotify()?;
+
+        let (request_tx, request_rx) = tokio::sync::mpsc::channel(self.request_buffer);
+        let (drop_tx, drop_rx) = tokio::sync::mpsc::unbounded_channel();
+        let handle = Handle {
+            request_tx,
+            drop_tx,
+            fatal: Default::default(),
+        };
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+
+        let mut state = WatcherState::new(
+            request_rx,
+            drop_rx,
+            shutdown_rx,
+            handle.fatal.clone(),
+            Some(inotify),
+            self.name,
+            None,
+            self.expected_watches,
+        )?;
+        state.create_retry(self.create_retry);
+        state.timestamps(self.timestamps);
+
+        let watcher = WatcherFuture {
+            task: Box::pin(Box::new(state).into_future(None)),
+            _shutdown: shutdown_tx,
+        };
+
+        Ok((handle, watcher))
+    }
+
+    /// The instance given to [`with_inotify`][`Builder::with_inotify`], or a new one
+    fn inotify(&mut self) -> Result<Inotify, InitError> {
+        if let Some(inotify) = self.inotify.take() {
+            return Ok(inotify);
+        }
+        if !self.init_flags.contains(InitFlags::IN_NONBLOCK) {
+            return Err(InitError::Blocking(self.init_flags));
+        }
+
+        Ok(Inotify::init(self.init_flags)?)
+    }
+
+    /// Launch the watcher task onto `runtime`, or the current runtime, after installing every
+    /// pre-watch
+    fn launch(
+        mut self,
+        runtime: Option<tokio::runtime::Handle>,
+        ready: Option<OnceSend<()>>,
+    ) -> Result<(OwnedHandle, Result<Vec<PreWatched>, AnotifyError>), InitError> {
+        // The inotify fd is registered with, and the task spawned onto, whichever runtime is
+        // entered
+        let _entered = runtime
+            .as_ref()
+            .filter(|_| !self.dedicated_thread)
+            .map(tokio::runtime::Handle::enter);
+        // Checked up front, as registering the inotify fd and spawning the task would panic
+        if !self.dedicated_thread && tokio::runtime::Handle::try_current().is_err() {
+            return Err(InitError::NoRuntime);
+        }
+        if self.raise_limits {
+            raise_limits();
+        }
+        let inotify = self.inotify()?;
+
+        let (request_tx, request_rx) = tokio::sync::mpsc::channel(self.request_buffer);
+        let (drop_tx, drop_rx) = tokio::sync::mpsc::unbounded_channel();
+        let inner = Handle {
+            request_tx,
+            drop_tx,
+            fatal: Default::default(),
+        };
+        let fatal = inner.fatal.clone();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let parked = Arc::new(Parked::default());
+
+        let (join, watched) = if self.dedicated_thread {
+            let (join_tx, join_rx) = std::sync::mpsc::sync_channel(1);
+            let name = self.name;
+            let expected_watches = self.expected_watches;
+            let create_retry = self.create_retry;
+            let timestamps = self.timestamps;
+            let pre_watches = self.pre_watches;
+            let handle = inner.clone();
+            let parked = parked.clone();
+
+            std::thread::Builder::new()
+                .name(name.clone())
+                .spawn(move || {
+                    let runtime = match tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                    {
+                        Ok(runtime) => runtime,
+                        Err(e) => {
+                            let _ = join_tx.send(Err(InitError::Thread(e)));
+                            return;
+                        }
+                    };
+
+                    runtime.block_on(async move {
+                        let mut state = match WatcherState::new(
+                            request_rx,
+                            drop_rx,
+                            shutdown_rx,
+                            fatal,
+                            Some(inotify),
+                            name,
+                            None,
+                            expected_watches,
+                        ) {
+                            Ok(state) => state,
+                            Err(e) => {
+                                let _ = join_tx.send(Err(e));
+                                return;
+                            }
+                        };
+
+                        // Keep driving this runtime until the task exits or is aborted
+                        let (exit_tx, exit_rx) = tokio::sync::oneshot::channel();
+                        state.exit_guard(exit_tx);
+                        let watched = pre_watch(&mut state, &handle, pre_watches);
+                        state.create_retry(create_retry);
+                        state.timestamps(timestamps);
+                        if let Some(ready) = ready {
+                            state.ready_signal(ready);
+                        }
+
+                        let join = WatcherState::launch(Box::new(state), parked);
+                        let _ = join_tx.send(Ok((join, watched)));
+                        let _ = exit_rx.await;
+                    });
+                })
+                .map_err(InitError::Thread)?;
+
+            join_rx
+                .recv()
+                .expect("anotify thread exited before launching the watcher task")?
+        } else {
+            let mut state = WatcherState::new(
+                request_rx,
+                drop_rx,
+                shutdown_rx,
+                fatal,
+                Some(inotify),
+                self.name,
+                None,
+                self.expected_watches,
+            )?;
+            let watched = pre_watch(&mut state, &inner, self.pre_watches);
+            state.create_retry(self.create_retry);
+            state.timestamps(self.timestamps);
+            if let Some(ready) = ready {
+                state.ready_signal(ready);
+            }
+
+            (
+                WatcherState::launch(Box::new(state), parked.clone()),
+                watched,
+            )
+        };
+
+        let handle = OwnedHandle {
+            inner,
+            join,
+            shutdown: shutdown_tx,
+            parked,
+        };
+
+        Ok((handle, watched))
+    }
+}
+
+/// Raise the soft limit on open files as far as allowed, and log the inotify limits
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn raise_limits() {
+    match getrlimit(Resource::RLIMIT_NOFILE) {
+        Ok((soft, hard)) if soft < hard => match setrlimit(Resource::RLIMIT_NOFILE, hard, hard) {
+            Ok(()) => {
+                crate::info!("Raised the open file limit from {soft} to {hard}");
+            }
+            Err(e) => {
+                crate::warn!("Could not raise the open file limit from {soft} to {hard}: {e}");
+            }
+        },
+        Ok((soft, _)) => {
+            crate::debug!("The open file limit is already at its maximum of {soft}");
+        }
+        Err(e) => {
+            crate::warn!("Could not read the open file limit: {e}");
+        }
+    }
+
+    for limit in [
+        "max_user_watches",
+        "max_user_instances",
+        "max_queued_events",
+    ] {
+        match std::fs::read_to_string(format!("/proc/sys/fs/inotify/{limit}")) {
+            Ok(value) => {
+                crate::info!("fs.inotify.{limit} is {}", value.trim());
+            }
+            Err(e) => {
+                crate::debug!("Could not read fs.inotify.{limit}: {e}");
+            }
+        }
+    }
+}
+
+/// Install every pre-watch on `state`, stopping at the first which fails
+fn pre_watch(
+    state: &mut WatcherState,
+    handle: &Handle,
+    pre_watches: Vec<PreWatch>,
+) -> Result<Vec<PreWatched>, AnotifyError> {
+    pre_watches
+        .into_iter()
+        .map(|watch| watch.install(state, handle))
+        .collect()
+}
@@ -1,11 +1,19 @@
 use displaydoc::Display;
 use thiserror::Error;
 
+use crate::handle::{RequestError, WatchError};
+
 /// Top level error that can be used to collect more specific errors yielded by library components
 #[derive(Debug, Error, Display)]
 pub enum AnotifyError {
     /// Failure to initialize the Anotify Watch Handler
     Init(InitError),
+
+    /// Failure to communicate with the Anotify Watch Handler
+    Watch(WatchError),
+
+    /// The requested path cannot be watched
+    Request(RequestError),
 }
 
 /// Failure to initialize the Anotify Watch Handler
@@ -16,6 +24,15 @@ pub enum InitError {
 
     /// Failed to register inotify instance instance with tokio io driver
     AsyncFd(#[from] std::io::Error),
+
+    /// Failed to start the dedicated watcher thread
+    Thread(std::io::Error),
+
+    /// Not called from within a tokio runtime, and not using a dedicated thread
+    NoRuntime,
+
+    /// Inotify init flags {0:?} do not include IN_NONBLOCK, which the watcher task relies on
+    Blocking(nix::sys::inotify::InitFlags),
 }
 
 macro_rules! intoerror {
@@ -44,4 +61,6 @@ macro_rules! intoerror {
 
 intoerror! {
     InitError => Init(it);
+    WatchError => Watch(it);
+    RequestError => Request(it);
 }
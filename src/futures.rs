@@ -1,27 +1,92 @@
 use std::{
+    collections::{BTreeMap, VecDeque},
     fmt::{Display, Formatter},
     future::Future,
+    path::{Path, PathBuf},
     pin::Pin,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime},
 };
 
-use nix::sys::inotify::{AddWatchFlags, WatchDescriptor};
-use tokio::sync::oneshot::Receiver as OnceRecv;
-use tokio_stream::{wrappers::ReceiverStream, Stream};
+use nix::{errno::Errno, sys::inotify::AddWatchFlags};
+use thiserror::Error;
+use tokio::{
+    io::AsyncWrite,
+    sync::{
+        broadcast::Receiver as BroadcastRecv, mpsc::Sender as MpscSend,
+        oneshot::Receiver as OnceRecv,
+    },
+    task::JoinHandle,
+    time::Sleep,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    Stream, StreamExt,
+};
 
-use crate::handle::Handle;
+use crate::{
+    error::AnotifyError,
+    handle::{Handle, WatchError},
+    ring::RingReceiver,
+    sink::{self, LogFormat},
+    task::{WatchOptions, WatchToken},
+};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FileWatchEvent {
     Read,
     Write,
     Open,
-    Close { writable: bool },
+    Close {
+        writable: bool,
+    },
+    Create,
+    Delete,
+    /// Permissions, ownership, timestamps, or other metadata changed
+    Metadata,
+    /// The watched path itself was moved, see
+    /// [`self_events`][`crate::handle::WatchRequest::self_events`], or for directory watches an
+    /// entry was moved, see [`moves`][`crate::handle::WatchRequest::moves`]
+    Moved,
+    /// Sent by the watcher task when there have been no other events for a while, see
+    /// [`heartbeat`][`crate::handle::WatchRequest::heartbeat`]
+    Heartbeat,
+    /// Sent by the watcher task when something went wrong which the watch can continue after,
+    /// see [`errors`][`crate::handle::WatchRequest::errors`]
+    ///
+    /// [`EventError::Fatal`] is sent to every watch regardless, as the last event before it ends.
+    Error(EventError),
+}
+
+/// A problem the watcher task ran into, which may have affected a watch's events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum EventError {
+    /// inotify's event queue overflowed, so events have been lost
+    #[error("inotify's event queue overflowed, so events have been lost")]
+    QueueOverflow,
+    /// inotify could not change which events are captured for the watched path
+    #[error("inotify could not update the watch, got errno {0}")]
+    Update(Errno),
+    /// The watcher task stopped because of this error, so the watch ends after this event
+    #[error("The watcher task failed, got errno {0}")]
+    Fatal(Errno),
+}
+
+impl std::hash::Hash for EventError {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        // Errno is not Hash, so use its value
+        if let EventError::Update(errno) | EventError::Fatal(errno) = self {
+            (*errno as i32).hash(state);
+        }
+    }
 }
 
 impl TryFrom<AddWatchFlags> for FileWatchEvent {
     type Error = String;
 
-    fn try_from(it: AddWatchFlags) -> Result<Self, Self::Error> {
+    fn try_from(it: AddWatchFlags) -> Result<Self, String> {
         use FileWatchEvent::*;
         match it {
             AddWatchFlags::IN_ACCESS => Ok(Read),
@@ -29,6 +94,10 @@ impl TryFrom<AddWatchFlags> for FileWatchEvent {
             AddWatchFlags::IN_OPEN => Ok(Open),
             AddWatchFlags::IN_CLOSE_NOWRITE => Ok(Close { writable: false }),
             AddWatchFlags::IN_CLOSE_WRITE => Ok(Close { writable: true }),
+            AddWatchFlags::IN_CREATE => Ok(Create),
+            AddWatchFlags::IN_DELETE => Ok(Delete),
+            AddWatchFlags::IN_ATTRIB => Ok(Metadata),
+            AddWatchFlags::IN_MOVE_SELF => Ok(Moved),
             otherwise => Err(format!(
                 "FileWatchEvent does not cover the bitpattern 0x{otherwise:8X}"
             )),
@@ -36,6 +105,86 @@ impl TryFrom<AddWatchFlags> for FileWatchEvent {
     }
 }
 
+/// The flags which can produce an event, and so which a watch needs to capture it
+///
+/// Events sent by the watcher task itself, such as heartbeats, map to no flags.
+impl From<&FileWatchEvent> for AddWatchFlags {
+    fn from(event: &FileWatchEvent) -> Self {
+        use FileWatchEvent::*;
+        match *event {
+            Read => AddWatchFlags::IN_ACCESS,
+            Write => AddWatchFlags::IN_MODIFY,
+            Open => AddWatchFlags::IN_OPEN,
+            Close { writable: false } => AddWatchFlags::IN_CLOSE_NOWRITE,
+            Close { writable: true } => AddWatchFlags::IN_CLOSE_WRITE,
+            // Entries renamed into or out of a directory appear or disappear for watches which
+            // follow a file by name
+            Create => AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO,
+            Delete => AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM,
+            Metadata => AddWatchFlags::IN_ATTRIB,
+            Moved => AddWatchFlags::IN_MOVE_SELF | AddWatchFlags::IN_MOVED_FROM,
+            Heartbeat | Error(_) => AddWatchFlags::empty(),
+        }
+    }
+}
+
+impl FileWatchEvent {
+    /// The event for a mask read from inotify, if it describes one
+    ///
+    /// `IN_ISDIR` is ignored, as it describes the event's subject rather than what happened to
+    /// it. Moves into or out of a directory map to [`Create`][`FileWatchEvent::Create`] and
+    /// [`Delete`][`FileWatchEvent::Delete`]. Pairing the two halves of a rename into a single
+    /// [`Moved`][`FileWatchEvent::Moved`] needs the events around it, so is left to the watcher
+    /// task.
+    pub fn from_mask(mask: AddWatchFlags) -> Option<Self> {
+        let mask = mask.difference(AddWatchFlags::IN_ISDIR);
+
+        match mask.try_into() {
+            Ok(event) => Some(event),
+            Err(_) if mask.contains(AddWatchFlags::IN_MOVED_TO) => Some(FileWatchEvent::Create),
+            Err(_) if mask.contains(AddWatchFlags::IN_MOVED_FROM) => Some(FileWatchEvent::Delete),
+            Err(_) => None,
+        }
+    }
+
+    /// Weather a watch capturing `flags` could receive this event
+    pub fn matches(&self, flags: AddWatchFlags) -> bool {
+        AddWatchFlags::from(self).intersects(flags)
+    }
+}
+
+/// Names for the event and constraint flags which can be set on a watch, in display order
+const FLAG_NAMES: &[(AddWatchFlags, &str)] = &[
+    (AddWatchFlags::IN_ACCESS, "Read"),
+    (AddWatchFlags::IN_MODIFY, "Write"),
+    (AddWatchFlags::IN_OPEN, "Open"),
+    (AddWatchFlags::IN_CLOSE_WRITE, "Close (modified)"),
+    (AddWatchFlags::IN_CLOSE_NOWRITE, "Close (unmodified)"),
+    (AddWatchFlags::IN_CREATE, "Create"),
+    (AddWatchFlags::IN_DELETE, "Delete"),
+    (AddWatchFlags::IN_ATTRIB, "Metadata"),
+    (AddWatchFlags::IN_MOVED_FROM, "Moved From"),
+    (AddWatchFlags::IN_MOVED_TO, "Moved To"),
+    (AddWatchFlags::IN_DELETE_SELF, "Self Deleted"),
+    (AddWatchFlags::IN_MOVE_SELF, "Self Moved"),
+    (AddWatchFlags::IN_ONLYDIR, "(only directories)"),
+    (AddWatchFlags::IN_DONT_FOLLOW, "(don't follow links)"),
+    (AddWatchFlags::IN_ONESHOT, "(oneshot)"),
+];
+
+/// Render a watch's flags as a human readable list, such as `"Write, Close (modified), Create"`
+///
+/// Flags which constrain the watch rather than select events are listed last, in parentheses.
+/// Flags without a name are omitted.
+pub fn describe(flags: AddWatchFlags) -> String {
+    FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 impl Display for FileWatchEvent {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         use FileWatchEvent::*;
@@ -43,6 +192,12 @@ impl Display for FileWatchEvent {
             Read => write!(f, "read"),
             Write => write!(f, "written"),
             Open => write!(f, "opened"),
+            Create => write!(f, "created"),
+            Delete => write!(f, "deleted"),
+            Metadata => write!(f, "changed"),
+            Moved => write!(f, "moved"),
+            Heartbeat => write!(f, "idle"),
+            Error(e) => write!(f, "not watched correctly: {e}"),
             Close { writable } => write!(
                 f,
                 "closed {}",
@@ -56,15 +211,218 @@ impl Display for FileWatchEvent {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// An event delivered to a watch
+///
+/// Equality and hashing ignore the [`timestamp`][`DirectoryWatchEvent::timestamp`] and
+/// [`seq`][`DirectoryWatchEvent::seq`], so that the same change compares equal however it was
+/// timed, see [`same_occurrence`][`DirectoryWatchEvent::same_occurrence`] to compare them too.
+#[derive(Debug, Clone)]
 pub struct DirectoryWatchEvent {
-    pub inner_path: Option<String>,
+    /// Name of the entry within the directory which the event concerned, or its path depending
+    /// on the watch's [`path_style`][`crate::handle::WatchRequest::path_style`]
+    ///
+    /// This is shared between every watcher which receives the event, so cloning it is cheap.
+    pub inner_path: Option<Arc<str>>,
     pub event: FileWatchEvent,
+    /// Weather the event concerned a directory
+    ///
+    /// This is reported by inotify with the event, so remains accurate for entries which have
+    /// since been deleted.
+    pub is_dir: bool,
+    /// The mask reported by inotify for this event, if requested with
+    /// [`raw_mask`][`crate::handle::WatchRequest::raw_mask`]
+    pub raw_mask: Option<u32>,
+    /// Whether the watch which delivered this event was registered on a file or a directory
+    pub watch_kind: WatchKind,
+    /// For an entry which was [`Moved`][`FileWatchEvent::Moved`] within the directory, its new
+    /// name, built the same way as [`inner_path`][`DirectoryWatchEvent::inner_path`]
+    ///
+    /// `None` when the entry was moved out of the directory.
+    pub moved_to: Option<Arc<str>>,
+    /// The event's number within its watch, counting from one, if the watch keeps a
+    /// [`history`][`crate::handle::WatchRequest::history`]
+    ///
+    /// Pass the last one seen to
+    /// [`Handle::replay_since`][`crate::handle::Handle::replay_since`] to catch up on anything
+    /// missed after it.
+    pub seq: Option<u64>,
+    /// When the watcher task read the event, see
+    /// [`Builder::timestamps`][`crate::builder::Builder::timestamps`]
+    pub timestamp: Timestamp,
+}
+
+/// When the watcher task read an event, in the representation chosen with
+/// [`Builder::timestamps`][`crate::builder::Builder::timestamps`]
+///
+/// Events read together share a timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timestamp {
+    #[default]
+    None,
+    Monotonic(Instant),
+    Wall(SystemTime),
+    Both(Instant, SystemTime),
+}
+
+impl Timestamp {
+    /// The monotonic time, for measuring latency, if it was recorded
+    pub fn monotonic(&self) -> Option<Instant> {
+        match *self {
+            Timestamp::Monotonic(at) | Timestamp::Both(at, _) => Some(at),
+            _ => None,
+        }
+    }
+
+    /// The wall clock time, for logging, if it was recorded
+    pub fn wall(&self) -> Option<SystemTime> {
+        match *self {
+            Timestamp::Wall(at) | Timestamp::Both(_, at) => Some(at),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of path a watch was registered on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WatchKind {
+    File,
+    Directory,
+}
+
+/// Identifies a single watch for as long as its instance is running, see
+/// [`FileWatchStream::id`]
+///
+/// Ids are never reused within an instance, and are kept by
+/// [`rewatch`][`FileWatchStream::rewatch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct WatchId(pub(crate) u64);
+
+impl Display for WatchId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "watch#{}", self.0)
+    }
+}
+
+/// Why the watcher task stopped sending events to a watch, see [`FileWatchStream::end_reason`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EndReason {
+    /// The watched path was deleted, so inotify removed the watch
+    FileRemoved,
+    /// The filesystem holding the watched path was unmounted, so inotify removed the watch
+    ///
+    /// Unlike [`FileRemoved`][`EndReason::FileRemoved`] the path may well exist again once the
+    /// filesystem is remounted, so it can be worth watching it again then.
+    Unmounted,
+    /// The watch outlived its [`expire_after`][`crate::handle::WatchRequest::expire_after`]
+    Expired,
+    /// The watch was removed by [`Handle::unwatch_prefix`]
+    Unwatched,
+    /// An event was dropped because the stream's buffer was full, see
+    /// [`Overflow::ErrorClose`][`crate::handle::Overflow::ErrorClose`]
+    Overflowed,
+    /// The watch delivered as many events as it was created for, see
+    /// [`watch_take`][`crate::handle::WatchRequest::watch_take`]
+    Taken,
+    /// The watcher task exited
+    InstanceClosed,
+    /// The watcher task stopped because of this error, see [`FileWatchStream::error`]
+    Failed(Errno),
+}
+
+impl std::hash::Hash for EndReason {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        // Errno is not Hash, so use its value
+        if let EndReason::Failed(errno) = self {
+            (*errno as i32).hash(state);
+        }
+    }
+}
+
+impl PartialEq for DirectoryWatchEvent {
+    fn eq(&self, other: &Self) -> bool {
+        // Destructured so that new fields cannot be missed
+        let Self {
+            inner_path,
+            event,
+            is_dir,
+            raw_mask,
+            watch_kind,
+            moved_to,
+            seq: _,
+            timestamp: _,
+        } = self;
+
+        *inner_path == other.inner_path
+            && *event == other.event
+            && *is_dir == other.is_dir
+            && *raw_mask == other.raw_mask
+            && *watch_kind == other.watch_kind
+            && *moved_to == other.moved_to
+    }
+}
+
+impl Eq for DirectoryWatchEvent {}
+
+impl std::hash::Hash for DirectoryWatchEvent {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        let Self {
+            inner_path,
+            event,
+            is_dir,
+            raw_mask,
+            watch_kind,
+            moved_to,
+            seq: _,
+            timestamp: _,
+        } = self;
+
+        inner_path.hash(state);
+        event.hash(state);
+        is_dir.hash(state);
+        raw_mask.hash(state);
+        watch_kind.hash(state);
+        moved_to.hash(state);
+    }
+}
+
+/// The events a watch kept after a cursor, see
+/// [`Handle::replay_since`][`crate::handle::Handle::replay_since`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replay {
+    /// Every event after the cursor, oldest first
+    Events(Vec<DirectoryWatchEvent>),
+    /// Some events after the cursor are no longer kept, so whatever was watched has to be
+    /// re-scanned instead
+    Gap,
+}
+
+impl DirectoryWatchEvent {
+    /// Weather this is the same event as `other`, read at the same time
+    ///
+    /// Unlike `==` this also compares the [`timestamp`][`DirectoryWatchEvent::timestamp`] and
+    /// [`seq`][`DirectoryWatchEvent::seq`], so is only meaningful with
+    /// [`Builder::timestamps`][`crate::builder::Builder::timestamps`] enabled or a
+    /// [`history`][`crate::handle::WatchRequest::history`] kept.
+    pub fn same_occurrence(&self, other: &Self) -> bool {
+        self == other && self.timestamp == other.timestamp && self.seq == other.seq
+    }
+
+    /// The flags set in [`raw_mask`][`DirectoryWatchEvent::raw_mask`], if it was requested
+    ///
+    /// Bits which nix does not know about are dropped, so use `raw_mask` directly for those.
+    pub fn raw_flags(&self) -> Option<AddWatchFlags> {
+        self.raw_mask.map(AddWatchFlags::from_bits_truncate)
+    }
 }
 
 impl Display for DirectoryWatchEvent {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        if let Some(ref inner_path) = self.inner_path {
+        if let (Some(ref inner_path), Some(ref moved_to)) = (&self.inner_path, &self.moved_to) {
+            write!(f, "{inner_path} was moved to {moved_to}")
+        } else if let Some(ref inner_path) = self.inner_path {
             write!(f, "{inner_path} was {}", self.event)
         } else {
             write!(f, "a file was {}", self.event)
@@ -75,25 +433,82 @@ impl Display for DirectoryWatchEvent {
 /// Single Event File Watch
 pub struct FileWatchFuture {
     pub(crate) inner: OnceRecv<DirectoryWatchEvent>,
-    pub(crate) watch_token: WatchDescriptor,
+    pub(crate) watch_token: WatchToken,
+    pub(crate) path: Arc<Path>,
     pub(crate) handle: Handle,
-    pub(crate) closed: bool,
+    pub(crate) end: Arc<OnceLock<EndReason>>,
 }
 pub struct FileWatchStream {
-    pub(crate) inner: ReceiverStream<DirectoryWatchEvent>,
-    pub(crate) watch_token: WatchDescriptor,
+    pub(crate) inner: Inbox,
+    /// Events buffered before a resize, which are delivered before `inner`
+    pub(crate) backlog: VecDeque<DirectoryWatchEvent>,
+    pub(crate) watch_token: WatchToken,
+    pub(crate) path: Arc<Path>,
     pub(crate) handle: Handle,
+    pub(crate) end: Arc<OnceLock<EndReason>>,
 }
 pub struct DirectoryWatchFuture {
     pub(crate) inner: OnceRecv<DirectoryWatchEvent>,
-    pub(crate) watch_token: WatchDescriptor,
+    pub(crate) watch_token: WatchToken,
+    pub(crate) path: Arc<Path>,
     pub(crate) handle: Handle,
-    pub(crate) closed: bool,
+    pub(crate) end: Arc<OnceLock<EndReason>>,
 }
 pub struct DirectoryWatchStream {
-    pub(crate) inner: ReceiverStream<DirectoryWatchEvent>,
-    pub(crate) watch_token: WatchDescriptor,
+    pub(crate) inner: Inbox,
+    /// Events buffered before a resize, which are delivered before `inner`
+    pub(crate) backlog: VecDeque<DirectoryWatchEvent>,
+    pub(crate) watch_token: WatchToken,
+    pub(crate) path: Arc<Path>,
+    pub(crate) handle: Handle,
+    pub(crate) end: Arc<OnceLock<EndReason>>,
+}
+
+/// Keeps a watch with a batch handler installed, see
+/// [`WatchRequest::watch_batches`][`crate::handle::WatchRequest::watch_batches`]
+///
+/// The watch is removed once this is dropped.
+pub struct BatchWatch {
+    pub(crate) watch_token: WatchToken,
+    pub(crate) path: Arc<Path>,
+    pub(crate) handle: Handle,
+    pub(crate) end: Arc<OnceLock<EndReason>>,
+}
+
+/// Where a stream receives its events from, depending on its
+/// [`Overflow`][`crate::handle::Overflow`] policy
+pub(crate) enum Inbox {
+    Buffered(ReceiverStream<DirectoryWatchEvent>),
+    Ring(RingReceiver<DirectoryWatchEvent>),
+}
+
+/// Stream of file events which yields the full event, see [`FileWatchStream::detailed`]
+#[derive(Debug)]
+pub struct DetailedFileWatchStream(FileWatchStream);
+
+/// Stream of file events which can be cloned, see
+/// [`WatchRequest::shared`][`crate::handle::WatchRequest::shared`]
+pub struct SharedFileWatchStream {
+    pub(crate) inner: BroadcastStream<DirectoryWatchEvent>,
+    /// Never read, only kept so that clones can subscribe
+    pub(crate) subscribe: BroadcastRecv<DirectoryWatchEvent>,
+    pub(crate) guard: Arc<SharedWatchGuard>,
+}
+/// Stream of directory events which can be cloned, see
+/// [`WatchRequest::shared`][`crate::handle::WatchRequest::shared`]
+pub struct SharedDirectoryWatchStream {
+    pub(crate) inner: BroadcastStream<DirectoryWatchEvent>,
+    /// Never read, only kept so that clones can subscribe
+    pub(crate) subscribe: BroadcastRecv<DirectoryWatchEvent>,
+    pub(crate) guard: Arc<SharedWatchGuard>,
+}
+
+/// Removes a shared watch once every clone has been dropped
+pub(crate) struct SharedWatchGuard {
+    pub(crate) watch_token: WatchToken,
+    pub(crate) path: Arc<Path>,
     pub(crate) handle: Handle,
+    pub(crate) end: Arc<OnceLock<EndReason>>,
 }
 
 impl Future for FileWatchFuture {
@@ -127,9 +542,8 @@ impl Stream for FileWatchStream {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        Pin::new(&mut self.inner)
-            .poll_next(cx)
-            .map(|it| it.map(|event| event.event))
+        let this = &mut *self;
+        poll_resized(&mut this.backlog, &mut this.inner, cx).map(|it| it.map(|event| event.event))
     }
 }
 
@@ -137,6 +551,43 @@ impl Stream for DirectoryWatchStream {
     // TODO(josiah) update this so that the item type can be WatchResult<WatchEvent>
     type Item = DirectoryWatchEvent;
 
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        poll_resized(&mut this.backlog, &mut this.inner, cx)
+    }
+}
+
+impl Stream for DetailedFileWatchStream {
+    type Item = DirectoryWatchEvent;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = &mut self.0;
+        poll_resized(&mut this.backlog, &mut this.inner, cx)
+    }
+}
+
+impl Stream for SharedFileWatchStream {
+    type Item = Result<FileWatchEvent, BroadcastStreamRecvError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|it| it.map(|event| event.map(|event| event.event)))
+    }
+}
+
+impl Stream for SharedDirectoryWatchStream {
+    type Item = Result<DirectoryWatchEvent, BroadcastStreamRecvError>;
+
     fn poll_next(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -144,3 +595,735 @@ impl Stream for DirectoryWatchStream {
         Pin::new(&mut self.inner).poll_next(cx)
     }
 }
+
+macro_rules! shared_clone {
+    ($($ty:ident),*) => {
+        $(
+            /// The clone will receive every event after this point
+            impl Clone for $ty {
+                fn clone(&self) -> Self {
+                    Self {
+                        inner: BroadcastStream::new(self.subscribe.resubscribe()),
+                        subscribe: self.subscribe.resubscribe(),
+                        guard: self.guard.clone(),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+shared_clone!(SharedFileWatchStream, SharedDirectoryWatchStream);
+
+impl Inbox {
+    fn len(&self) -> usize {
+        match self {
+            Inbox::Buffered(inner) => inner.as_ref().len(),
+            Inbox::Ring(inner) => inner.len(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Inbox::Buffered(inner) => inner.as_ref().max_capacity(),
+            Inbox::Ring(inner) => inner.capacity(),
+        }
+    }
+}
+
+/// Take the events left over from before a resize, and then poll the current receiver
+fn poll_resized(
+    backlog: &mut VecDeque<DirectoryWatchEvent>,
+    inner: &mut Inbox,
+    cx: &mut std::task::Context<'_>,
+) -> std::task::Poll<Option<DirectoryWatchEvent>> {
+    match backlog.pop_front() {
+        Some(event) => std::task::Poll::Ready(Some(event)),
+        None => match inner {
+            Inbox::Buffered(inner) => Pin::new(inner).poll_next(cx),
+            Inbox::Ring(inner) => inner.poll_recv(cx),
+        },
+    }
+}
+
+impl FileWatchEvent {
+    /// Weather this is the same kind of event as `other`, ignoring any data carried by the event
+    pub fn same_kind(&self, other: &FileWatchEvent) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl FileWatchStream {
+    /// Only yield events of the same kind as `kind`, see [`FileWatchEvent::same_kind`]
+    pub fn filter_kind(self, kind: FileWatchEvent) -> impl Stream<Item = FileWatchEvent> {
+        StreamExt::filter(self, move |event| event.same_kind(&kind))
+    }
+
+    /// Yield [`Error`][`FileWatchEvent::Error`] events as errors, see
+    /// [`errors`][`crate::handle::WatchRequest::errors`]
+    pub fn results(self) -> impl Stream<Item = Result<FileWatchEvent, EventError>> {
+        StreamExt::map(self, |event| match event {
+            FileWatchEvent::Error(e) => Err(e),
+            event => Ok(event),
+        })
+    }
+}
+
+impl FileWatchStream {
+    /// Yield the full event for each file event, rather than just its kind
+    ///
+    /// Useful for merging with directory streams, as both then yield [`DirectoryWatchEvent`]
+    /// and can be told apart by [`watch_kind`][`DirectoryWatchEvent::watch_kind`].
+    pub fn detailed(self) -> DetailedFileWatchStream {
+        DetailedFileWatchStream(self)
+    }
+
+    /// Write every event to `sink` in `format`, see [`sink::log_to`]
+    pub fn log_to<W>(self, sink: W, format: LogFormat) -> JoinHandle<std::io::Result<W>>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        sink::log_to(self.detailed(), sink, format)
+    }
+}
+
+impl DirectoryWatchStream {
+    /// Yield the path and kind of each event as a tuple
+    pub fn kinds(self) -> impl Stream<Item = (Option<Arc<str>>, FileWatchEvent)> {
+        StreamExt::map(self, |event| (event.inner_path, event.event))
+    }
+
+    /// Only yield events of the same kind as `kind`, see [`FileWatchEvent::same_kind`]
+    pub fn filter_kind(self, kind: FileWatchEvent) -> impl Stream<Item = DirectoryWatchEvent> {
+        StreamExt::filter(self, move |event| event.event.same_kind(&kind))
+    }
+
+    /// Yield [`Error`][`FileWatchEvent::Error`] events as errors, see
+    /// [`errors`][`crate::handle::WatchRequest::errors`]
+    pub fn results(self) -> impl Stream<Item = Result<DirectoryWatchEvent, EventError>> {
+        StreamExt::map(self, |event| match event.event {
+            FileWatchEvent::Error(e) => Err(e),
+            _ => Ok(event),
+        })
+    }
+
+    /// Write every event to `sink` in `format`, see [`sink::log_to`]
+    pub fn log_to<W>(self, sink: W, format: LogFormat) -> JoinHandle<std::io::Result<W>>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        sink::log_to(self, sink, format)
+    }
+
+    /// Split this into a separate stream for each kind of event
+    ///
+    /// Events are forwarded from this stream by a task spawned onto the current runtime, so this
+    /// must be called from within one. The watch is removed once every stream has been dropped,
+    /// while dropping only some of them just discards the events they would have received.
+    ///
+    /// Each stream only holds a single event, so the forwarding task waits for a slow stream to
+    /// take its event before forwarding any more, to any of them. Events then build up in this
+    /// watch's [`buffer`][`crate::handle::WatchRequest::buffer`] instead, and are dropped according
+    /// to its [`overflow`][`crate::handle::WatchRequest::overflow`] policy once it is full.
+    pub fn split_by_kind(mut self) -> KindStreams {
+        let (read_tx, read) = kind_channel();
+        let (write_tx, write) = kind_channel();
+        let (open_tx, open) = kind_channel();
+        let (close_tx, close) = kind_channel();
+        let (create_tx, create) = kind_channel();
+        let (delete_tx, delete) = kind_channel();
+        let (metadata_tx, metadata) = kind_channel();
+        let (moved_tx, moved) = kind_channel();
+        let (heartbeat_tx, heartbeat) = kind_channel();
+        let (error_tx, error) = kind_channel();
+
+        tokio::spawn(async move {
+            let all_closed = async {
+                for tx in [
+                    &read_tx,
+                    &write_tx,
+                    &open_tx,
+                    &close_tx,
+                    &create_tx,
+                    &delete_tx,
+                    &metadata_tx,
+                    &moved_tx,
+                    &heartbeat_tx,
+                    &error_tx,
+                ] {
+                    tx.closed().await;
+                }
+            };
+            tokio::pin!(all_closed);
+
+            loop {
+                let event = tokio::select! {
+                    _ = &mut all_closed => break,
+                    event = self.next() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                };
+
+                let tx = match event.event {
+                    FileWatchEvent::Read => &read_tx,
+                    FileWatchEvent::Write => &write_tx,
+                    FileWatchEvent::Open => &open_tx,
+                    FileWatchEvent::Close { .. } => &close_tx,
+                    FileWatchEvent::Create => &create_tx,
+                    FileWatchEvent::Delete => &delete_tx,
+                    FileWatchEvent::Metadata => &metadata_tx,
+                    FileWatchEvent::Moved => &moved_tx,
+                    FileWatchEvent::Heartbeat => &heartbeat_tx,
+                    FileWatchEvent::Error(_) => &error_tx,
+                };
+
+                // Only fails if that stream was dropped
+                let _ = tx.send(event).await;
+            }
+        });
+
+        KindStreams {
+            read,
+            write,
+            open,
+            close,
+            create,
+            delete,
+            metadata,
+            moved,
+            heartbeat,
+            error,
+        }
+    }
+}
+
+/// Forward items from `stream` on a spawned task until `until` completes, then drop `stream`
+pub(crate) fn forward_until<S, F>(mut stream: S, until: F) -> ReceiverStream<S::Item>
+where
+    S: Stream + Unpin + Send + 'static,
+    S::Item: Send,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+
+    tokio::spawn(async move {
+        tokio::pin!(until);
+
+        loop {
+            let item = tokio::select! {
+                _ = &mut until => break,
+                _ = tx.closed() => break,
+                item = stream.next() => match item {
+                    Some(item) => item,
+                    None => break,
+                },
+            };
+
+            tokio::select! {
+                _ = &mut until => break,
+                sent = tx.send(item) => if sent.is_err() {
+                    break;
+                },
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+fn kind_channel() -> (MpscSend<DirectoryWatchEvent>, KindStream) {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    (tx, KindStream(ReceiverStream::new(rx)))
+}
+
+/// Directory events split into a stream per kind, see [`DirectoryWatchStream::split_by_kind`]
+#[derive(Debug)]
+pub struct KindStreams {
+    pub read: KindStream,
+    pub write: KindStream,
+    pub open: KindStream,
+    pub close: KindStream,
+    pub create: KindStream,
+    pub delete: KindStream,
+    pub metadata: KindStream,
+    pub moved: KindStream,
+    pub heartbeat: KindStream,
+    pub error: KindStream,
+}
+
+/// Stream of a single kind of directory event, see [`DirectoryWatchStream::split_by_kind`]
+#[derive(Debug)]
+pub struct KindStream(ReceiverStream<DirectoryWatchEvent>);
+
+impl Stream for KindStream {
+    type Item = DirectoryWatchEvent;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+/// An entry in a watched directory, see [`DirectoryStateStream`]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DirEntry {
+    pub name: Arc<str>,
+    pub is_dir: bool,
+}
+
+/// Stream of every entry in a directory, yielded after each change, see
+/// [`WatchRequest::watch_state`][`crate::handle::WatchRequest::watch_state`]
+///
+/// Entries are yielded in order of their names. The watch is removed once this is dropped.
+pub struct DirectoryStateStream {
+    pub(crate) inner: DirectoryWatchStream,
+    path: PathBuf,
+    options: WatchOptions,
+    entries: BTreeMap<Arc<str>, bool>,
+    listed: bool,
+    /// Listing the directory again on the blocking pool, after events were lost
+    relisting: Option<JoinHandle<std::io::Result<Entries>>>,
+}
+
+type Entries = BTreeMap<Arc<str>, bool>;
+
+impl DirectoryStateStream {
+    /// List `path`, which `inner` must already be watching
+    pub(crate) async fn new(
+        inner: DirectoryWatchStream,
+        path: PathBuf,
+        options: WatchOptions,
+    ) -> std::io::Result<Self> {
+        let entries = list_blocking(path.clone(), options.clone()).await?;
+
+        Ok(Self {
+            inner,
+            path,
+            options,
+            entries,
+            listed: false,
+            relisting: None,
+        })
+    }
+
+    /// Apply `event` to the entries, returning weather they changed
+    fn apply(&mut self, event: DirectoryWatchEvent) -> bool {
+        let Some(name) = event.inner_path else {
+            return false;
+        };
+
+        match event.event {
+            FileWatchEvent::Create => self.entries.insert(name, event.is_dir) != Some(event.is_dir),
+            FileWatchEvent::Delete => self.entries.remove(&name).is_some(),
+            FileWatchEvent::Moved => {
+                let removed = self.entries.remove(&name).is_some();
+
+                match event.moved_to {
+                    Some(to) if self.options.wants_entry(Some(&to), event.is_dir) => {
+                        self.entries.insert(to, event.is_dir) != Some(event.is_dir) || removed
+                    }
+                    _ => removed,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn snapshot(&self) -> Vec<DirEntry> {
+        self.entries
+            .iter()
+            .map(|(name, &is_dir)| DirEntry {
+                name: name.clone(),
+                is_dir,
+            })
+            .collect()
+    }
+}
+
+/// Stream of a file's contents, read again after each change, see
+/// [`WatchRequest::watch_contents`][`crate::handle::WatchRequest::watch_contents`]
+///
+/// The watch is removed once this is dropped.
+pub struct ContentsStream {
+    inner: FileWatchStream,
+    path: PathBuf,
+    debounce: Duration,
+    /// When to read the file next, once changes have stopped for the debounce
+    read_at: Option<Pin<Box<Sleep>>>,
+    /// The read in progress on the blocking pool, if any
+    reading: Option<JoinHandle<std::io::Result<Vec<u8>>>>,
+    /// Whether the inner stream has ended
+    ended: bool,
+}
+
+impl ContentsStream {
+    /// Read `path` straight away, which `inner` must already be watching
+    pub(crate) fn new(inner: FileWatchStream, path: PathBuf, debounce: Duration) -> Self {
+        Self {
+            inner,
+            path,
+            debounce,
+            read_at: Some(Box::pin(tokio::time::sleep(Duration::ZERO))),
+            reading: None,
+            ended: false,
+        }
+    }
+}
+
+impl Stream for ContentsStream {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            // Take every change which has already arrived, so that a burst only restarts the wait
+            while !this.ended {
+                match Pin::new(&mut this.inner).poll_next(cx) {
+                    std::task::Poll::Ready(Some(FileWatchEvent::Delete)) => {}
+                    std::task::Poll::Ready(Some(_)) => {
+                        this.read_at = Some(Box::pin(tokio::time::sleep(this.debounce)));
+                    }
+                    std::task::Poll::Ready(None) => this.ended = true,
+                    std::task::Poll::Pending => break,
+                }
+            }
+
+            // Changes during a read restart the wait, and are read again once it is over
+            if let Some(ref mut reading) = this.reading {
+                let result = std::task::ready!(Pin::new(reading).poll(cx))
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                this.reading = None;
+
+                match result {
+                    // Replaced part way through, so wait for it to be created again
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    result => return std::task::Poll::Ready(Some(result)),
+                }
+            }
+
+            let Some(ref mut read_at) = this.read_at else {
+                return match this.ended {
+                    true => std::task::Poll::Ready(None),
+                    false => std::task::Poll::Pending,
+                };
+            };
+            std::task::ready!(read_at.as_mut().poll(cx));
+            this.read_at = None;
+
+            // The file may be large or on a slow filesystem, so is read off the runtime's workers
+            let path = this.path.clone();
+            this.reading = Some(tokio::task::spawn_blocking(move || std::fs::read(path)));
+        }
+    }
+}
+
+/// Read the entries of the directory at `path` which pass the watch's filters
+fn list(path: &std::path::Path, options: &WatchOptions) -> std::io::Result<Entries> {
+    let mut entries = BTreeMap::new();
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        // Names which aren't unicode can't be reported by events either
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let name = Arc::<str>::from(name);
+        let is_dir = entry.file_type()?.is_dir();
+
+        if options.wants_entry(Some(&name), is_dir) {
+            entries.insert(name, is_dir);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// [`list`] on the blocking pool, as the directory may be large or on a slow filesystem
+async fn list_blocking(path: PathBuf, options: WatchOptions) -> std::io::Result<Entries> {
+    tokio::task::spawn_blocking(move || list(&path, &options))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
+impl Stream for DirectoryStateStream {
+    type Item = Vec<DirEntry>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if !this.listed {
+            this.listed = true;
+            return std::task::Poll::Ready(Some(this.snapshot()));
+        }
+
+        loop {
+            // Events after the overflow wait in the stream until the listing is done, and are
+            // then applied on top of it, which leaves the same entries if it already saw them
+            if let Some(ref mut relisting) = this.relisting {
+                let listed = std::task::ready!(Pin::new(relisting).poll(cx));
+                this.relisting = None;
+
+                if let Ok(Ok(entries)) = listed {
+                    if entries != this.entries {
+                        this.entries = entries;
+                        return std::task::Poll::Ready(Some(this.snapshot()));
+                    }
+                }
+            }
+
+            let Some(event) = std::task::ready!(Pin::new(&mut this.inner).poll_next(cx)) else {
+                return std::task::Poll::Ready(None);
+            };
+
+            match event.event {
+                // Events were lost, so start again from the directory as it is now
+                FileWatchEvent::Error(EventError::QueueOverflow) => {
+                    let (path, options) = (this.path.clone(), this.options.clone());
+                    this.relisting =
+                        Some(tokio::task::spawn_blocking(move || list(&path, &options)));
+                }
+                _ => {
+                    if this.apply(event) {
+                        return std::task::Poll::Ready(Some(this.snapshot()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+macro_rules! stream_control {
+    ($($ty:ty => $dir:literal),*) => {
+        $(
+            impl $ty {
+                /// Start delivering events matching `flags` to this stream, as well as those it
+                /// was already interested in
+                ///
+                /// Fails with [`WatchError::Stale`] if the watched path has since been replaced by
+                /// another file, as inotify can only update a watch through its path.
+                pub async fn add_interest(&mut self, flags: AddWatchFlags) -> Result<(), WatchError> {
+                    crate::handle::validate_flags(flags, $dir)?;
+                    self.handle
+                        .interest(self.watch_token, flags, AddWatchFlags::empty())
+                        .await
+                }
+
+                /// Stop delivering events matching `flags` to this stream
+                pub async fn remove_interest(
+                    &mut self,
+                    flags: AddWatchFlags,
+                ) -> Result<(), WatchError> {
+                    self.handle
+                        .interest(self.watch_token, AddWatchFlags::empty(), flags)
+                        .await
+                }
+
+                /// Stop delivering events to this stream until [`unmute`][`Self::unmute`] is called
+                ///
+                /// The inotify watch stays installed, and events are dropped rather than buffered,
+                /// so this suits ignoring changes the consumer makes itself. Events which were
+                /// already buffered are still delivered, and heartbeats and errors are not muted.
+                /// Events are read separately from requests, so those caused by a change made
+                /// just before muting or unmuting may land on either side of it.
+                pub async fn mute(&mut self) -> Result<(), WatchError> {
+                    self.handle.mute(self.watch_token, true).await
+                }
+
+                /// Resume delivering events after [`mute`][`Self::mute`]
+                pub async fn unmute(&mut self) -> Result<(), WatchError> {
+                    self.handle.mute(self.watch_token, false).await
+                }
+
+                /// The number of events this stream can buffer, see
+                /// [`buffer`][`crate::handle::WatchRequest::buffer`]
+                pub fn capacity(&self) -> usize {
+                    self.inner.capacity()
+                }
+
+                /// The number of events buffered and waiting to be taken
+                ///
+                /// Once this reaches [`capacity`][`Self::capacity`], new events are dropped
+                /// according to the stream's [`overflow`][`crate::handle::WatchRequest::overflow`]
+                /// policy, so a consumer falling behind can notice before that happens. Includes
+                /// events kept from before a [`resize_buffer`][`Self::resize_buffer`], so may
+                /// briefly exceed the capacity.
+                pub fn len(&self) -> usize {
+                    self.backlog.len() + self.inner.len()
+                }
+
+                /// Weather no events are waiting to be taken
+                pub fn is_empty(&self) -> bool {
+                    self.len() == 0
+                }
+
+                /// Move this watch onto `path`, keeping any events already buffered
+                ///
+                /// The new path is watched before the old one is removed, so no events are missed
+                /// in between.
+                pub async fn rewatch(&mut self, path: impl Into<PathBuf>) -> Result<(), AnotifyError> {
+                    let path = crate::handle::check_path(path.into(), $dir)?;
+                    self.watch_token = self.handle.rewatch(self.watch_token, path.clone()).await?;
+                    self.path = path.into();
+
+                    Ok(())
+                }
+
+                /// Replace the buffer for this stream with one which can hold `size` events
+                ///
+                /// Events which were already buffered are kept, and are delivered before any new
+                /// events. With [`DropOldest`][`crate::handle::Overflow::DropOldest`] the buffer is
+                /// resized in place instead, so shrinking it drops the oldest events which no
+                /// longer fit. Zero is treated as one, as with
+                /// [`buffer`][`crate::handle::WatchRequest::buffer`].
+                ///
+                /// Fails with [`WatchError::Closed`] if the watch has already been removed, leaving
+                /// the stream as it was.
+                pub async fn resize_buffer(&mut self, size: usize) -> Result<(), WatchError> {
+                    let size = size.max(1);
+                    if let Inbox::Ring(ring) = &self.inner {
+                        ring.resize(size);
+                        return Ok(());
+                    }
+
+                    let inner = self.handle.resize(self.watch_token, size).await?;
+                    let old = std::mem::replace(&mut self.inner, Inbox::Buffered(inner));
+
+                    if let Inbox::Buffered(old) = old {
+                        let mut old = old.into_inner();
+
+                        // The task has dropped the old sender, so this takes everything it sent
+                        while let Ok(event) = old.try_recv() {
+                            self.backlog.push_back(event);
+                        }
+                    }
+
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+stream_control!(FileWatchStream => false, DirectoryWatchStream => true);
+
+macro_rules! watch_status {
+    ($($ty:ty $(=> $field:ident)?),*) => {
+        $(
+            impl $ty {
+                /// The error which stopped the watcher task, if it exited because of one
+                ///
+                /// The watch is sent a final [`EventError::Fatal`] with the same error first, and
+                /// ends with [`EndReason::Failed`].
+                pub fn error(&self) -> Option<Errno> {
+                    self$(.$field)?.handle.error()
+                }
+
+                /// The id of this watch, which is shared with any streams deduplicated onto it
+                pub fn id(&self) -> WatchId {
+                    WatchId(self$(.$field)?.watch_token.id)
+                }
+
+                /// Why the watcher task stopped sending events, once it has
+                ///
+                /// Set before the last event is delivered, so is always available once this has
+                /// ended.
+                pub fn end_reason(&self) -> Option<EndReason> {
+                    self$(.$field)?.end.get().copied()
+                }
+
+                /// The path this watch was created for
+                fn watched_path(&self) -> &Path {
+                    &self$(.$field)?.path
+                }
+            }
+        )*
+    };
+}
+
+watch_status!(
+    FileWatchFuture,
+    FileWatchStream,
+    DirectoryWatchFuture,
+    DirectoryWatchStream,
+    SharedFileWatchStream => guard,
+    SharedDirectoryWatchStream => guard,
+    BatchWatch
+);
+
+/// Show the watch a type refers to, rather than its channel
+macro_rules! debug_watch {
+    ($($ty:ident),*) => {
+        $(
+            impl std::fmt::Debug for $ty {
+                fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                    f.debug_struct(stringify!($ty))
+                        .field("id", &self.id())
+                        .field("path", &self.watched_path())
+                        .field("end_reason", &self.end_reason())
+                        .finish_non_exhaustive()
+                }
+            }
+        )*
+    };
+}
+
+debug_watch!(
+    FileWatchFuture,
+    FileWatchStream,
+    DirectoryWatchFuture,
+    DirectoryWatchStream,
+    SharedFileWatchStream,
+    SharedDirectoryWatchStream,
+    BatchWatch
+);
+
+impl std::fmt::Debug for ContentsStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentsStream")
+            .field("id", &self.inner.id())
+            .field("path", &self.path)
+            .field("debounce", &self.debounce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Debug for DirectoryStateStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirectoryStateStream")
+            .field("id", &self.inner.id())
+            .field("path", &self.path)
+            .field("entries", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+macro_rules! drop_watch {
+    ($($ty:ty),*) => {
+        $(
+            impl Drop for $ty {
+                fn drop(&mut self) {
+                    self.handle.dropped(self.watch_token);
+                }
+            }
+        )*
+    };
+}
+
+drop_watch!(
+    FileWatchFuture,
+    FileWatchStream,
+    DirectoryWatchFuture,
+    DirectoryWatchStream,
+    SharedWatchGuard,
+    BatchWatch
+);
@@ -1,45 +1,120 @@
-use nix::sys::inotify::AddWatchFlags;
+use nix::{
+    errno::Errno,
+    sys::{
+        inotify::{AddWatchFlags, InitFlags},
+        statfs::{statfs, FsType, DEBUGFS_MAGIC, PROC_SUPER_MAGIC, SYSFS_MAGIC, TRACEFS_MAGIC},
+    },
+};
 use std::{
+    future::Future,
     marker::PhantomData,
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
     time::Duration,
 };
 use thiserror::Error;
 use tokio::{
-    sync::{mpsc::Sender as MpscSend, oneshot::Sender as OnceSend},
+    sync::{
+        mpsc::{error::TrySendError, Sender as MpscSend, UnboundedSender},
+        oneshot::Sender as OnceSend,
+    },
     task::JoinHandle,
 };
-use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{
+    wrappers::{BroadcastStream, ReceiverStream},
+    Stream,
+};
 
 use crate::{
-    futures::{DirectoryWatchFuture, DirectoryWatchStream, FileWatchFuture, FileWatchStream},
-    task::WatchRequestInner,
+    builder::PreWatched,
+    error::AnotifyError,
+    futures::{
+        forward_until, BatchWatch, ContentsStream, DirectoryStateStream, DirectoryWatchEvent,
+        DirectoryWatchFuture, DirectoryWatchStream, EndReason, FileWatchEvent, FileWatchFuture,
+        FileWatchStream, Inbox, Replay, SharedDirectoryWatchStream, SharedFileWatchStream,
+        SharedWatchGuard, WatchId, WatchKind,
+    },
+    snapshot::RegistrySnapshot,
+    stats::Stats,
+    task::{
+        BatchHandler, Parked, ShutdownSignal, Subscription, WatchOptions, WatchRequestInner,
+        WatchToken, WatcherState, IN_MASK_ADD, IN_MASK_CREATE,
+    },
 };
 
 #[derive(Debug, Clone)]
 pub struct Handle {
     pub(crate) request_tx: MpscSend<WatchRequestInner>,
+    pub(crate) drop_tx: UnboundedSender<WatchToken>,
+    pub(crate) fatal: Arc<OnceLock<Errno>>,
 }
 
 #[derive(Debug)]
 pub struct OwnedHandle {
     pub(crate) inner: Handle,
-    pub(crate) shutdown: OnceSend<()>,
+    pub(crate) shutdown: OnceSend<ShutdownSignal>,
     pub(crate) join: JoinHandle<()>,
+    /// Where the task's state is left if its runtime is dropped, for [`OwnedHandle::rebind`]
+    pub(crate) parked: Arc<Parked>,
+}
+
+/// Aborts the watcher task, see [`OwnedHandle::abort_handle`]
+#[derive(Debug, Clone)]
+pub struct AbortHandle {
+    inner: tokio::task::AbortHandle,
+    parked: Arc<Parked>,
+}
+
+impl AbortHandle {
+    /// Abort the watcher task, dropping every watch
+    pub fn abort(&self) {
+        self.parked.abandon();
+        self.inner.abort();
+    }
+
+    /// Weather the watcher task has stopped, whether it exited or was aborted
+    pub fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
 }
 
 impl OwnedHandle {
     pub const DEFAULT_SHUTDOWN: Duration = Duration::from_secs(2);
     pub const DEFAULT_REQUEST_BUFFER: usize = 32;
 
-    pub async fn shutdown_with(mut self, wait: Duration) {
-        let _ = self.shutdown.send(());
+    pub async fn shutdown_with(self, wait: Duration) {
+        self.finish(wait, None).await;
+    }
+
+    /// Shut down like [`shutdown_with`][`OwnedHandle::shutdown_with`], reporting what was still
+    /// being watched when the task exited
+    ///
+    /// Useful for finding watches which were leaked. Requests queued before the shutdown are
+    /// handled first, so are included.
+    pub async fn shutdown_with_report(self, wait: Duration) -> ShutdownReport {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let clean = self.finish(wait, Some(tx)).await;
+
+        match rx.await {
+            Ok(report) => ShutdownReport { clean, ..report },
+            Err(_) => ShutdownReport::default(),
+        }
+    }
+
+    /// Shut down the task, returning weather it exited by itself within `wait`
+    async fn finish(mut self, wait: Duration, report: ShutdownSignal) -> bool {
+        let _ = self.shutdown.send(report);
 
         let join = tokio::time::timeout(wait, &mut self.join);
 
         match join.await {
-            Err(_) => self.join.abort(),
+            Err(_) => {
+                self.parked.abandon();
+                self.join.abort();
+                false
+            }
             Ok(Err(e)) => {
                 if e.is_cancelled() {
                     panic!("The Watch Task was cancelled without consuming the OwnedHandle");
@@ -47,7 +122,7 @@ impl OwnedHandle {
 
                 std::panic::resume_unwind(e.into_panic());
             }
-            Ok(Ok(())) => {}
+            Ok(Ok(())) => true,
         }
     }
 
@@ -58,6 +133,55 @@ impl OwnedHandle {
     pub async fn wait(self) -> Result<(), tokio::task::JoinError> {
         self.join.await
     }
+
+    /// Get a handle which can abort the watcher task, for supervisors which do not own this
+    ///
+    /// Unlike [`shutdown`][`OwnedHandle::shutdown`], aborting does not let the task finish what
+    /// it was doing, and it records no [`error`][`Handle::error`]. The aborted task is dropped,
+    /// which closes the inotify instance and so removes every watch from the kernel. The handle
+    /// refers to the current task, so is not carried over by [`rebind`][`OwnedHandle::rebind`].
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle {
+            inner: self.join.abort_handle(),
+            parked: self.parked.clone(),
+        }
+    }
+
+    /// Move the watcher task onto another runtime
+    ///
+    /// The inotify instance is kept, so existing watches continue to deliver events without
+    /// interruption. If the task's runtime has already been dropped, taking the task with it,
+    /// the task's state was kept, and is launched again from there. Watches and streams carry on
+    /// as they were, having missed nothing but the time without a task, as inotify holds on to
+    /// events until they are read.
+    pub async fn rebind(&mut self, runtime: tokio::runtime::Handle) -> Result<(), AnotifyError> {
+        let parked = self
+            .parked
+            .state
+            .lock()
+            .ok()
+            .and_then(|mut slot| slot.take());
+
+        let state = match parked {
+            Some(state) => state,
+            None => {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+
+                self.request_tx
+                    .try_send(WatchRequestInner::Detach(tx))
+                    .map_err(WatchError::from)?;
+
+                let state = rx.await.map_err(|_| WatchError::WatcherShutdown)?;
+                let _ = (&mut self.join).await;
+                state
+            }
+        };
+
+        let _guard = runtime.enter();
+        self.join = WatcherState::launch(state.reregister()?, self.parked.clone());
+
+        Ok(())
+    }
 }
 
 impl Deref for OwnedHandle {
@@ -74,61 +198,499 @@ impl DerefMut for OwnedHandle {
     }
 }
 
+/// What the watcher task was still watching when it shut down, see
+/// [`OwnedHandle::shutdown_with_report`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Weather the task exited by itself in time, rather than being aborted or having already
+    /// exited, in which case the counts are zero
+    pub clean: bool,
+    /// Number of inotify watches installed
+    pub watches: usize,
+    /// Number of watchers across every watch
+    pub watchers: usize,
+}
+
 #[derive(Debug, Error)]
 pub enum RequestError {
     #[error("There is no file or directory at the path: {0}")]
     DoesNotExist(PathBuf),
     #[error("The inode at {0} does not have the correct type for this operation")]
     IncorrectType(PathBuf),
+    #[error("The filesystem containing {0} does not generate inotify events")]
+    Unsupported(PathBuf),
 }
 
 #[derive(Debug, Error)]
 pub enum WatchError {
     #[error("The watcher task was shutdown while before the next event could be received")]
     WatcherShutdown,
+    #[error("The watcher task has too many pending requests to accept another")]
+    RequestBufferFull,
+    #[error("The flags {0:?} cannot be used for this watch")]
+    InvalidFlags(AddWatchFlags),
+    #[error("A watch cannot be limited to only directories and only files at the same time")]
+    ConflictingFlags,
+    #[error("The flags {0:?} are mutually exclusive")]
+    ExclusiveFlags(AddWatchFlags),
+    #[error("The watch does not capture any events, so could never produce one")]
+    NoEvents,
+    #[error("The watch has already been removed")]
+    Closed,
+    #[error("inotify could not install the watch, got errno {0}")]
+    Install(Errno),
+    #[error("The watched path no longer refers to the watched file")]
+    Stale,
+    #[error("inotify could not install the watch: {0}")]
+    Limit(ResourceLimit),
+    #[error("The file is already watched through another path")]
+    AlreadyWatched,
+    #[error("The directory could not be listed: {0}")]
+    List(std::io::Error),
+}
+
+/// A system limit which stopped a watch from being installed, and so which needs raising
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ResourceLimit {
+    /// `EMFILE`
+    #[error("the process has too many open files, raise its limit with `ulimit -n`")]
+    ProcessFiles,
+    /// `ENFILE`
+    #[error("the system has too many open files, raise `fs.file-max`")]
+    SystemFiles,
+    /// `ENOMEM`
+    #[error("the kernel is out of memory")]
+    Memory,
+    /// `ENOSPC`
+    #[error("the user has too many inotify watches, raise `fs.inotify.max_user_watches`")]
+    Watches,
+}
+
+impl ResourceLimit {
+    /// The limit which `errno` reports was reached, if it is one of them
+    pub fn from_errno(errno: Errno) -> Option<Self> {
+        match errno {
+            Errno::EMFILE => Some(Self::ProcessFiles),
+            Errno::ENFILE => Some(Self::SystemFiles),
+            Errno::ENOMEM => Some(Self::Memory),
+            Errno::ENOSPC => Some(Self::Watches),
+            _ => None,
+        }
+    }
+}
+
+impl From<Errno> for WatchError {
+    fn from(errno: Errno) -> Self {
+        if errno == Errno::EEXIST {
+            // Only returned for exclusive watches
+            return Self::AlreadyWatched;
+        }
+        if errno == Errno::ESTALE {
+            // Only returned when a watch's path has been replaced
+            return Self::Stale;
+        }
+
+        match ResourceLimit::from_errno(errno) {
+            Some(limit) => Self::Limit(limit),
+            None => Self::Install(errno),
+        }
+    }
+}
+
+impl From<TrySendError<WatchRequestInner>> for WatchError {
+    fn from(e: TrySendError<WatchRequestInner>) -> Self {
+        match e {
+            TrySendError::Full(_) => Self::RequestBufferFull,
+            TrySendError::Closed(_) => Self::WatcherShutdown,
+        }
+    }
 }
 
 impl Handle {
-    /// Create a file watch builder
-    pub fn file(&mut self, path: PathBuf) -> Result<WatchRequest<'_, FileEvents>, RequestError> {
-        if !path.exists() {
-            return Err(RequestError::DoesNotExist(path));
+    /// Add and remove events from what a single watcher is interested in
+    pub(crate) async fn interest(
+        &self,
+        token: WatchToken,
+        add: AddWatchFlags,
+        remove: AddWatchFlags,
+    ) -> Result<(), WatchError> {
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::Interest {
+            token,
+            add,
+            remove,
+            done,
+        })?;
+
+        done_rx
+            .await
+            .map_err(|_| WatchError::WatcherShutdown)?
+            .map_err(WatchError::from)
+    }
+
+    /// Move a watcher onto `path`, returning its new token
+    pub(crate) async fn rewatch(
+        &self,
+        token: WatchToken,
+        path: PathBuf,
+    ) -> Result<WatchToken, WatchError> {
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx
+            .try_send(WatchRequestInner::Rewatch { token, path, done })?;
+
+        done_rx
+            .await
+            .map_err(|_| WatchError::WatcherShutdown)?
+            .map_err(WatchError::from)?
+            .ok_or(WatchError::Closed)
+    }
+
+    /// Stop or resume delivering events to a single watcher
+    pub(crate) async fn mute(&self, token: WatchToken, muted: bool) -> Result<(), WatchError> {
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx
+            .try_send(WatchRequestInner::Mute { token, muted, done })?;
+
+        match done_rx.await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(WatchError::Closed),
+            Err(_) => Err(WatchError::WatcherShutdown),
         }
-        if path.is_dir() {
-            return Err(RequestError::IncorrectType(path));
+    }
+
+    /// Swap the sender for a stream watch with one for a new channel of `size`
+    pub(crate) async fn resize(
+        &self,
+        token: WatchToken,
+        size: usize,
+    ) -> Result<ReceiverStream<DirectoryWatchEvent>, WatchError> {
+        let (sender, rx) = tokio::sync::mpsc::channel(size);
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::Resize {
+            token,
+            sender,
+            done,
+        })?;
+
+        match done_rx.await {
+            Ok(true) => Ok(ReceiverStream::from(rx)),
+            Ok(false) => Err(WatchError::Closed),
+            Err(_) => Err(WatchError::WatcherShutdown),
         }
+    }
+
+    /// Notify the watcher task that a watch was dropped
+    ///
+    /// Drops are sent on their own unbounded channel, so they cannot be lost to a full request
+    /// channel.
+    pub(crate) fn dropped(&self, token: WatchToken) {
+        if self.drop_tx.send(token).is_err() {
+            crate::trace!("Watcher task already exited, not notifying of drop");
+        }
+    }
+
+    /// The error which stopped the watcher task, if it exited because of one
+    ///
+    /// Once set, every watch will end after a final
+    /// [`EventError::Fatal`][`crate::futures::EventError::Fatal`] event.
+    pub fn error(&self) -> Option<Errno> {
+        self.fatal.get().copied()
+    }
+
+    /// The number of requests which have been sent to the watcher task but not yet handled
+    ///
+    /// Producers registering many watches at once can use this to pace themselves, rather than
+    /// overrunning the request buffer.
+    pub fn pending_requests(&self) -> usize {
+        self.request_tx.max_capacity() - self.request_tx.capacity()
+    }
+
+    /// Get a snapshot of every watch held by the watcher task
+    ///
+    /// Meant for debugging, as this is more detailed (and more expensive) than
+    /// [`stats`][`Handle::stats`].
+    pub async fn dump(&self) -> Result<RegistrySnapshot, WatchError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::Dump(tx))?;
+
+        rx.await.map_err(|_| WatchError::WatcherShutdown)
+    }
+
+    /// Remove every watch on `prefix` or a path under it, returning how many watchers were removed
+    ///
+    /// Removed stream watches end, and removed single event watches resolve to `None`. Only the
+    /// path each watch was registered with is considered, so a watch on a directory above
+    /// `prefix` is kept even though it may report events for paths under it.
+    pub async fn unwatch_prefix(&self, prefix: impl Into<PathBuf>) -> Result<usize, WatchError> {
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::UnwatchPrefix {
+            prefix: prefix.into(),
+            done,
+        })?;
+
+        done_rx.await.map_err(|_| WatchError::WatcherShutdown)
+    }
+
+    /// Check weather there is currently an inotify watch on `path`
+    ///
+    /// Several watches on the same path share one inotify watch, so this only says that at least
+    /// one exists. Paths are compared as given, without resolving links or relative components.
+    pub async fn is_watching(&self, path: impl Into<PathBuf>) -> Result<bool, WatchError> {
+        let (done, done_rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::IsWatching {
+            path: path.into(),
+            done,
+        })?;
+
+        done_rx.await.map_err(|_| WatchError::WatcherShutdown)
+    }
+
+    /// Get the inotify flags a watch on a `kind` of path can capture or be configured with,
+    /// such as for [`raw_flags`][`WatchRequest::raw_flags`]
+    ///
+    /// Requesting any other flag fails with [`WatchError::InvalidFlags`] rather than the watch
+    /// silently never seeing those events.
+    pub fn supported_flags(&self, kind: WatchKind) -> AddWatchFlags {
+        supported_flags(kind == WatchKind::Directory)
+    }
+
+    /// Get the flags the inotify instance is open with, read from its file descriptor
+    ///
+    /// For auditing that it is non-blocking, and whether it is closed on exec, which matters when
+    /// the process spawns children. Reflects the instance as it is now, so is accurate for one
+    /// passed to [`with_inotify`][`crate::builder::Builder::with_inotify`] as well.
+    pub async fn init_flags(&self) -> Result<InitFlags, WatchError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::InitFlags(tx))?;
+
+        rx.await.map_err(|_| WatchError::WatcherShutdown)
+    }
+
+    /// Get the events the watch `id` has kept since the one numbered `after`, for catching up
+    /// after missing some, see [`history`][`WatchRequest::history`]
+    ///
+    /// Pass zero to replay from the start. Resolves to `None` if there is no live watch with the
+    /// id which keeps a history.
+    pub async fn replay_since(
+        &self,
+        id: WatchId,
+        after: u64,
+    ) -> Result<Option<Replay>, WatchError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::Replay {
+            id,
+            after,
+            done: tx,
+        })?;
+
+        rx.await.map_err(|_| WatchError::WatcherShutdown)
+    }
+
+    /// Get a snapshot of the watcher task's counters
+    pub async fn stats(&self) -> Result<Stats, WatchError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        self.request_tx.try_send(WatchRequestInner::Stats(tx))?;
+
+        rx.await.map_err(|_| WatchError::WatcherShutdown)
+    }
+
+    /// Create a file watch builder
+    ///
+    /// Takes anything which converts into a [`PathBuf`]:
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> Result<(), anotify::error::AnotifyError> {
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// let mut owner = anotify::new()?;
+    ///
+    /// let _from_str = owner.file("Cargo.toml")?.modify(true).watch().await?;
+    /// let _from_path = owner.file(Path::new("Cargo.toml"))?.open(true).next().await?;
+    /// let _from_path_buf = owner.file(PathBuf::from("Cargo.toml"))?.close(true).watch().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn file(
+        &mut self,
+        path: impl Into<PathBuf>,
+    ) -> Result<WatchRequest<'_, FileEvents>, RequestError> {
+        let path = check_path(path.into(), false)?;
 
         Ok(WatchRequest {
             handle: self,
             path,
-            buffer: FileEvents::DEFAULT_BUFFER,
-            flags: AddWatchFlags::empty(),
-            _type: Default::default(),
+            config: WatchConfig::new(),
         })
     }
 
     /// Create a directory watch builder
+    ///
+    /// Takes anything which converts into a [`PathBuf`]:
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> Result<(), anotify::error::AnotifyError> {
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// let mut owner = anotify::new()?;
+    ///
+    /// let _from_str = owner.dir("src")?.create(true).watch().await?;
+    /// let _from_path = owner.dir(Path::new("src"))?.delete(true).next().await?;
+    /// let _from_path_buf = owner.dir(PathBuf::from("src"))?.modify(true).watch().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
     pub fn dir(
         &mut self,
-        path: PathBuf,
+        path: impl Into<PathBuf>,
     ) -> Result<WatchRequest<'_, DirectoryEvents>, RequestError> {
-        // TODO(josiah) make take Into<Path>
+        let path = check_path(path.into(), true)?;
 
-        if !path.exists() {
-            return Err(RequestError::DoesNotExist(path));
-        }
-        if !path.is_dir() {
+        Ok(WatchRequest {
+            handle: self,
+            path,
+            config: WatchConfig::new(),
+        })
+    }
+
+    /// Create a file watch builder for a path which may not have been created yet
+    ///
+    /// Only the parent directory has to exist. Dispatch with
+    /// [`watch_atomic`][`WatchRequest::watch_atomic`], which delivers a
+    /// [`Create`][`crate::futures::FileWatchEvent::Create`] once the file appears, then the file's
+    /// events. If it is deleted the stream carries on, and delivers another `Create` if it is
+    /// created again. Other dispatch methods fail unless the file exists by then.
+    pub fn eventual_file(
+        &mut self,
+        path: impl Into<PathBuf>,
+    ) -> Result<WatchRequest<'_, FileEvents>, RequestError> {
+        let path = path.into();
+        if path.is_dir() || path.file_name().is_none() {
             return Err(RequestError::IncorrectType(path));
         }
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                check_path(parent.to_owned(), true)?;
+            }
+            _ => {}
+        }
 
         Ok(WatchRequest {
             handle: self,
             path,
-            buffer: DirectoryEvents::DEFAULT_BUFFER,
-            flags: AddWatchFlags::empty(),
-            _type: Default::default(),
+            config: WatchConfig::new(),
+        })
+    }
+
+    /// Create a watch builder for `path` using a copy of `config`
+    ///
+    /// Checks `path` the same way as [`file`][`Handle::file`] or [`dir`][`Handle::dir`].
+    pub fn apply<T: WatchType>(
+        &mut self,
+        config: &WatchConfig<T>,
+        path: impl Into<PathBuf>,
+    ) -> Result<WatchRequest<'_, T>, RequestError> {
+        let path = check_path(path.into(), T::DIR)?;
+
+        Ok(WatchRequest {
+            handle: self,
+            path,
+            config: config.clone(),
         })
     }
+
+    /// Create a file watch builder for `name` relative to the open directory `dir`
+    ///
+    /// Useful for sandboxed processes which only hold directory fds rather than absolute paths.
+    pub fn file_at(
+        &mut self,
+        dir: &impl AsRawFd,
+        name: impl AsRef<Path>,
+    ) -> Result<WatchRequest<'_, FileEvents>, RequestError> {
+        self.file(path_at(dir, name))
+    }
+
+    /// Create a directory watch builder for `name` relative to the open directory `dir`
+    ///
+    /// Event paths are reported relative to the watched directory, as with [`Handle::dir`].
+    pub fn dir_at(
+        &mut self,
+        dir: &impl AsRawFd,
+        name: impl AsRef<Path>,
+    ) -> Result<WatchRequest<'_, DirectoryEvents>, RequestError> {
+        self.dir(path_at(dir, name))
+    }
+}
+
+/// Filesystems which inotify will accept watches on, but which do not generate events for
+/// changes to their contents
+const PSEUDO_FILESYSTEMS: &[FsType] =
+    &[PROC_SUPER_MAGIC, SYSFS_MAGIC, DEBUGFS_MAGIC, TRACEFS_MAGIC];
+
+/// Check that `path` exists, is a directory if `dir` or a file otherwise, and can be watched
+pub(crate) fn check_path(path: PathBuf, dir: bool) -> Result<PathBuf, RequestError> {
+    if !path.exists() {
+        return Err(RequestError::DoesNotExist(path));
+    }
+    if path.is_dir() != dir {
+        return Err(RequestError::IncorrectType(path));
+    }
+    if is_pseudo_fs(&path) {
+        return Err(RequestError::Unsupported(path));
+    }
+
+    Ok(path)
+}
+
+fn is_pseudo_fs(path: &Path) -> bool {
+    statfs(path)
+        .map(|fs| PSEUDO_FILESYSTEMS.contains(&fs.filesystem_type()))
+        .unwrap_or(false)
+}
+
+/// The flags which can be requested by a file or directory watch
+fn supported_flags(dir: bool) -> AddWatchFlags {
+    // Only ever reported by inotify, or would remove the watch out from under other watchers
+    let mut invalid = AddWatchFlags::IN_ISDIR
+        | AddWatchFlags::IN_IGNORED
+        | AddWatchFlags::IN_Q_OVERFLOW
+        | AddWatchFlags::IN_UNMOUNT
+        | AddWatchFlags::IN_ONESHOT;
+    if !dir {
+        invalid |= AddWatchFlags::IN_ONLYDIR;
+    }
+
+    AddWatchFlags::all() - invalid
+}
+
+/// Check that `flags` can be requested by a file or directory watch
+pub(crate) fn validate_flags(flags: AddWatchFlags, dir: bool) -> Result<(), WatchError> {
+    if flags.contains(IN_MASK_ADD | IN_MASK_CREATE) {
+        return Err(WatchError::ExclusiveFlags(IN_MASK_ADD | IN_MASK_CREATE));
+    }
+
+    let invalid = flags - supported_flags(dir);
+    if !invalid.is_empty() {
+        return Err(WatchError::InvalidFlags(invalid));
+    }
+
+    Ok(())
+}
+
+/// Resolve `name` relative to the open directory `dir` through procfs
+fn path_at(dir: &impl AsRawFd, name: impl AsRef<Path>) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd())).join(name)
 }
 
 mod sealed {
@@ -137,6 +699,7 @@ mod sealed {
 
 pub trait WatchType: sealed::Sealed {
     const DEFAULT_BUFFER: usize;
+    const DIR: bool;
 }
 
 pub enum FileEvents {}
@@ -147,119 +710,842 @@ impl sealed::Sealed for DirectoryEvents {}
 
 impl WatchType for FileEvents {
     const DEFAULT_BUFFER: usize = 16;
+    const DIR: bool = false;
 }
 
 impl WatchType for DirectoryEvents {
     const DEFAULT_BUFFER: usize = 32;
+    const DIR: bool = true;
 }
 
 /// Configuration and dispatch for a watch
 pub struct WatchRequest<'handle, T: WatchType> {
     handle: &'handle mut Handle,
     path: PathBuf,
+    config: WatchConfig<T>,
+}
+
+/// Configuration for a watch which is not tied to a path or handle
+///
+/// This has the same configuration methods as [`WatchRequest`], and can be cloned and applied to
+/// any number of paths with [`Handle::apply`].
+pub struct WatchConfig<T: WatchType> {
     buffer: usize,
+    overflow: Overflow,
     flags: AddWatchFlags,
+    options: WatchOptions,
     _type: PhantomData<T>,
 }
 
-/// # Common Configuration Methods
-impl<T: WatchType> WatchRequest<'_, T> {
-    /// Set the amount of items for this watch to buffer,
-    ///
-    /// value is not considered for single event watches
-    pub fn buffer(mut self, size: usize) -> Self {
-        self.buffer = size;
-        self
+impl<T: WatchType> WatchConfig<T> {
+    /// Create a config which captures no events, with the default buffer for this kind of watch
+    pub fn new() -> Self {
+        Self {
+            buffer: T::DEFAULT_BUFFER,
+            overflow: Default::default(),
+            flags: AddWatchFlags::empty(),
+            options: Default::default(),
+            _type: Default::default(),
+        }
     }
 
-    /// Set weather file read events should be captured
-    pub fn read(mut self, set: bool) -> Self {
-        self.flags.set(AddWatchFlags::IN_ACCESS, set);
+    fn config_mut(&mut self) -> &mut WatchConfig<T> {
         self
     }
 
-    /// Set weather file open events should be captured
-    pub fn modify(mut self, set: bool) -> Self {
-        self.flags.set(AddWatchFlags::IN_MODIFY, set);
-        self
+    /// A stream watch on `path` with this configuration, to install before the task starts
+    pub(crate) fn pre_watch(&self, path: PathBuf) -> PreWatch {
+        PreWatch {
+            path,
+            dir: T::DIR,
+            buffer: self.buffer,
+            overflow: self.overflow,
+            flags: self.flags,
+            options: self.options.clone(),
+        }
+    }
+}
+
+impl<T: WatchType> Default for WatchConfig<T> {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    /// Set weather file open events should be captured
-    pub fn open(mut self, set: bool) -> Self {
-        self.flags.set(AddWatchFlags::IN_OPEN, set);
-        self
+impl<T: WatchType> Clone for WatchConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            buffer: self.buffer,
+            overflow: self.overflow,
+            flags: self.flags,
+            options: self.options.clone(),
+            _type: Default::default(),
+        }
     }
+}
 
-    /// Set weather file close events should be generated
-    pub fn close(mut self, set: bool) -> Self {
-        self.flags.set(AddWatchFlags::IN_CLOSE, set);
-        self
+impl<T: WatchType> std::fmt::Debug for WatchConfig<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WatchConfig")
+            .field("buffer", &self.buffer)
+            .field("overflow", &self.overflow)
+            .field("flags", &self.flags)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// What a stream watch does with new events once its buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Keep the events already buffered, dropping new events until there is room
+    #[default]
+    DropNewest,
+    /// Drop the oldest buffered event to make room for each new event, so that the buffer always
+    /// holds the most recent events
+    DropOldest,
+    /// End the stream once an event has to be dropped, for consumers which cannot carry on
+    /// correctly after missing one
+    ///
+    /// Events buffered before the overflow are still delivered, after which the stream ends with
+    /// an [`end_reason`][`crate::futures::FileWatchStream::end_reason`] of
+    /// [`Overflowed`][`crate::futures::EndReason::Overflowed`].
+    ErrorClose,
+}
+
+/// Which event a single event watch resolves to, see [`once`][`WatchRequest::once`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Once {
+    /// The first event of a kind the watch captures
+    ///
+    /// Events of other kinds, captured for other watches on the same path, are skipped over.
+    #[default]
+    Matching,
+    /// The first event reported for the path, even if it is of a kind captured for another watch
+    /// on the same path rather than this one
+    Any,
+}
+
+/// How a directory watch reports the entries its events concern, see
+/// [`path_style`][`WatchRequest::path_style`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PathStyle {
+    /// Just the entry's name
+    #[default]
+    Bare,
+    /// The watched directory's path joined with the entry's name
+    ///
+    /// Relative only if the watch was created with a relative path.
+    JoinedWithWatch,
+    /// The entry's path relative to the watched directory
+    ///
+    /// Watches only cover the directory's direct entries, so this is currently the same as
+    /// [`Bare`][`PathStyle::Bare`].
+    RelativeToWatch,
+}
+
+/// Configuration methods shared by [`WatchRequest`] and [`WatchConfig`] for every kind of watch
+macro_rules! common_config {
+    () => {
+        /// Set the amount of items for this watch to buffer,
+        ///
+        /// value is not considered for single event watches. Zero is treated as one, as every
+        /// kind of buffer needs room for at least one event.
+        pub fn buffer(mut self, size: usize) -> Self {
+            self.config_mut().buffer = size.max(1);
+            self
+        }
+
+        /// Set what happens to new events once the buffer is full, [`Overflow::DropNewest`] by
+        /// default
+        ///
+        /// value is only considered for [`watch`][`WatchRequest::watch`]
+        pub fn overflow(mut self, overflow: Overflow) -> Self {
+            self.config_mut().overflow = overflow;
+            self
+        }
+
+        /// Set which event a single event watch resolves to, [`Once::Matching`] by default
+        ///
+        /// Only one event is ever delivered, so any others read along with it are dropped. value
+        /// is only considered for [`next`][`WatchRequest::next`]
+        pub fn once(mut self, once: Once) -> Self {
+            self.config_mut().options.once = once;
+            self
+        }
+
+        /// Set weather file read events should be captured
+        pub fn read(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_ACCESS, set);
+            self
+        }
+
+        /// Set weather file open events should be captured
+        pub fn modify(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_MODIFY, set);
+            self
+        }
+
+        /// Set weather writes should only be delivered once the writer closes the file
+        ///
+        /// A file is usually written in several parts, each of which would be delivered as a
+        /// [`Write`][`crate::futures::FileWatchEvent::Write`], so a consumer could see it half
+        /// written. Instead a single `Write` is delivered in place of the close which follows
+        /// them. Files opened for writing and closed without being written to deliver nothing.
+        pub fn write_complete(mut self, set: bool) -> Self {
+            self.config_mut().options.write_complete = set;
+            self
+        }
+
+        /// Set weather file open events should be captured
+        pub fn open(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_OPEN, set);
+            self
+        }
+
+        /// Set weather file close events should be generated
+        ///
+        /// Covers files closed with and without write access alike, which are told apart by
+        /// `writable` on [`Close`][`crate::futures::FileWatchEvent::Close`]. Use
+        /// [`close_write`][`WatchRequest::close_write`] or
+        /// [`close_nowrite`][`WatchRequest::close_nowrite`] for just one of them.
+        pub fn close(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_CLOSE, set);
+            self
+        }
+
+        /// Set weather close events for files opened with write access should be generated
+        pub fn close_write(mut self, set: bool) -> Self {
+            self.config_mut()
+                .flags
+                .set(AddWatchFlags::IN_CLOSE_WRITE, set);
+            self
+        }
+
+        /// Set weather close events for files opened without write access should be generated
+        pub fn close_nowrite(mut self, set: bool) -> Self {
+            self.config_mut()
+                .flags
+                .set(AddWatchFlags::IN_CLOSE_NOWRITE, set);
+            self
+        }
+
+        /// Set weather metadata changes, such as to permissions or timestamps, should be captured
+        pub fn metadata(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_ATTRIB, set);
+            self
+        }
+
+        /// Set weather the raw inotify mask should be attached to each event
+        ///
+        /// Only visible through
+        /// [`DirectoryWatchEvent::raw_mask`][`crate::futures::DirectoryWatchEvent::raw_mask`],
+        /// so file watches need [`detailed`][`crate::futures::FileWatchStream::detailed`] to see
+        /// it. Off by default.
+        pub fn raw_mask(mut self, set: bool) -> Self {
+            self.config_mut().options.raw_mask = set;
+            self
+        }
+
+        /// Keep the most recent `depth` events delivered to this watch, numbering each with its
+        /// [`seq`][`crate::futures::DirectoryWatchEvent::seq`]
+        ///
+        /// A consumer which misses events, such as one whose stream lagged or which resubscribes
+        /// to a [`shared`][`WatchRequest::shared`] watch, can then catch up with
+        /// [`Handle::replay_since`] rather than re-scanning, as long as it is no more than
+        /// `depth` events behind. Events dropped because the buffer was full are kept as well.
+        pub fn history(mut self, depth: usize) -> Self {
+            self.config_mut().options.history = depth;
+            self
+        }
+
+        /// Remove this watch once `duration` has elapsed, regardless of any events
+        ///
+        /// Streams will end after delivering any events which were already buffered, and single
+        /// event watches will resolve to `None` if no event was seen.
+        pub fn expire_after(mut self, duration: Duration) -> Self {
+            self.config_mut().options.expire = Some(duration);
+            self
+        }
+
+        /// Set weather problems the watcher task recovers from should be delivered as
+        /// [`Error`][`crate::futures::FileWatchEvent::Error`] events
+        ///
+        /// Otherwise these are only logged, so a watch may silently miss events. The watch carries
+        /// on afterwards, and `results` on the stream yields these as `Err` items. value is not
+        /// considered for single event watches
+        pub fn errors(mut self, set: bool) -> Self {
+            self.config_mut().options.errors = set;
+            self
+        }
+
+        /// Deliver a [`Heartbeat`][`crate::futures::FileWatchEvent::Heartbeat`] event whenever
+        /// no other event has been delivered for `interval`
+        ///
+        /// Heartbeats are sent by the watcher task, so receiving them shows that it is still
+        /// running even if the path is quiet. value is not considered for single event watches
+        pub fn heartbeat(mut self, interval: Duration) -> Self {
+            self.config_mut().options.heartbeat = Some(interval);
+            self
+        }
+
+        /// Set weather dispatch should fail with [`WatchError::AlreadyWatched`] if the file is
+        /// already watched by this instance through another path, such as a hard link
+        ///
+        /// inotify has one watch per inode, so by default the existing watch is shared and its
+        /// mask widened to cover both. Watches on the same path are always shared. Needs Linux
+        /// 4.18 or later, otherwise dispatch fails with [`WatchError::Install`].
+        pub fn exclusive(mut self, set: bool) -> Self {
+            self.config_mut().options.exclusive = set;
+            self
+        }
+
+        /// Add arbitrary inotify flags to this watch
+        ///
+        /// This is an escape hatch for flags which do not have their own builder method, and the
+        /// flags are passed to inotify as is. Flags which change how the watch itself behaves
+        /// (such as `IN_DONT_FOLLOW`) apply to every watch on the same path, so you're on your
+        /// own as far as how they interact with other watches.
+        ///
+        /// Flags which inotify only reports (such as `IN_ISDIR`), or which are not valid for this
+        /// kind of watch, will cause dispatch to fail with [`WatchError::InvalidFlags`]. So does
+        /// `IN_ONESHOT`, as the kernel would remove the watch for every other watcher on the
+        /// inode after the first event, see [`Once`] for single event watches instead. Setting
+        /// both `IN_MASK_ADD` and `IN_MASK_CREATE` fails with [`WatchError::ExclusiveFlags`], and
+        /// either one alone with [`WatchError::InvalidFlags`], as the watcher task sets them
+        /// itself, see [`exclusive`][`Self::exclusive`].
+        pub fn raw_flags(mut self, flags: AddWatchFlags) -> Self {
+            self.config_mut().flags |= flags;
+            self
+        }
+    };
+}
+
+/// Configuration methods shared by [`WatchRequest`] and [`WatchConfig`] for file watches
+macro_rules! file_config {
+    () => {
+        /// Set weather the watch ends once the file is removed, which is the default
+        ///
+        /// When unset, the watch is registered on the parent directory and follows whichever file
+        /// is at this path, so it waits for the file to be created again rather than ending. It
+        /// also delivers [`Create`][`crate::futures::FileWatchEvent::Create`] whenever a file is
+        /// created or renamed into place at this path, and
+        /// [`Delete`][`crate::futures::FileWatchEvent::Delete`] whenever it is deleted or renamed
+        /// away, see [`watch_atomic`][`WatchRequest::watch_atomic`].
+        pub fn auto_close_on_self_removal(mut self, set: bool) -> Self {
+            self.config_mut().options.follow_name = !set;
+            self
+        }
+    };
+}
+
+/// Configuration methods shared by [`WatchRequest`] and [`WatchConfig`] for directory watches
+macro_rules! directory_config {
+    () => {
+        /// Set weather creation of entries in the directory should be captured
+        pub fn create(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_CREATE, set);
+            self
+        }
+
+        /// Set weather deletion of entries in the directory should be captured
+        pub fn delete(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_DELETE, set);
+            self
+        }
+
+        /// Set weather entries being moved within, into, or out of the directory should be
+        /// captured
+        ///
+        /// A rename within the directory is delivered as a single
+        /// [`Moved`][`crate::futures::FileWatchEvent::Moved`] event for the old name, with the
+        /// new name in [`moved_to`][`crate::futures::DirectoryWatchEvent::moved_to`]. Entries
+        /// moved out are delivered as `Moved` without `moved_to`, and entries moved in as
+        /// [`Create`][`crate::futures::FileWatchEvent::Create`].
+        pub fn moves(mut self, set: bool) -> Self {
+            self.config_mut().flags.set(AddWatchFlags::IN_MOVE, set);
+            self
+        }
+
+        /// Hold back events for newly created entries until they have existed for `settle`
+        ///
+        /// If an entry is deleted before then, none of its events are delivered, not even the
+        /// delete. This keeps the temporary files editors create and then quickly remove from
+        /// reaching the watch. Entries which survive are delivered in their original order, after
+        /// `settle` has passed.
+        pub fn settle(mut self, settle: Duration) -> Self {
+            self.config_mut().options.settle = Some(settle);
+            self
+        }
+
+        /// Set weather only events for child directories should be delivered
+        ///
+        /// May not be used together with [`only_files`][`WatchRequest::only_files`].
+        pub fn only_dirs(mut self, set: bool) -> Self {
+            self.config_mut().options.only_dirs = set;
+            self
+        }
+
+        /// Set weather only events for child files should be delivered
+        ///
+        /// May not be used together with [`only_dirs`][`WatchRequest::only_dirs`].
+        pub fn only_files(mut self, set: bool) -> Self {
+            self.config_mut().options.only_files = set;
+            self
+        }
+
+        /// Set weather metadata changes to the directory itself, and it being moved, should be
+        /// delivered
+        ///
+        /// These arrive with no [`inner_path`][`crate::futures::DirectoryWatchEvent::inner_path`],
+        /// as [`Metadata`][`crate::futures::FileWatchEvent::Metadata`] and
+        /// [`Moved`][`crate::futures::FileWatchEvent::Moved`]. The watch follows the directory
+        /// when it is moved, so keeps delivering events for its entries afterwards.
+        pub fn self_events(mut self, set: bool) -> Self {
+            self.config_mut().options.self_events = set;
+            self
+        }
+
+        /// Set how [`inner_path`][`crate::futures::DirectoryWatchEvent::inner_path`] is built for
+        /// each event, [`PathStyle::Bare`] by default
+        ///
+        /// Only names which are valid UTF-8 are reported, so joined paths replace anything in the
+        /// watched directory's path which is not.
+        pub fn path_style(mut self, style: PathStyle) -> Self {
+            self.config_mut().options.path_style = style;
+            self
+        }
+
+        /// Only deliver events for entries with one of these exact names
+        ///
+        /// Events for the directory itself, and for entries whose names are not valid UTF-8,
+        /// have no name so are never delivered. Cheaper than filtering the stream, as events for
+        /// other entries are dropped before they reach it.
+        pub fn only_names<I>(mut self, names: I) -> Self
+        where
+            I: IntoIterator,
+            I::Item: Into<Arc<str>>,
+        {
+            self.config_mut().options.names = Some(names.into_iter().map(Into::into).collect());
+            self
+        }
+    };
+}
+
+/// # Common Configuration Methods
+impl<T: WatchType> WatchRequest<'_, T> {
+    common_config!();
+
+    /// Take a copy of this request's configuration, to apply to other paths
+    pub fn config(&self) -> WatchConfig<T> {
+        self.config.clone()
+    }
+
+    fn config_mut(&mut self) -> &mut WatchConfig<T> {
+        &mut self.config
     }
 
     // TODO(josiah) moves will require a more robust background task so that move events can be
     // coalesced correctly
-}
 
-/// # File Specific Dispatch Methods
-impl<'handle> WatchRequest<'handle, FileEvents> {
-    /// Create a watch which will only return the next captured event, and then unsubscribe
+    /// Create a watch which calls `handler` with every event from each batch read from inotify,
+    /// as one slice, rather than sending them through a channel
     ///
-    /// Ignores the value set by [`buffer`][`WatchRequest::buffer`]
-    pub async fn next(self) -> Result<FileWatchFuture, WatchError> {
-        let (sender, rx) = tokio::sync::oneshot::channel();
+    /// Suits high throughput consumers such as counters and metrics, as there is no per-event
+    /// send or wakeup. The handler is called on the watcher task, which cannot handle anything
+    /// else until it returns, so it must be quick and must not block or panic. File events are
+    /// delivered in full, as with [`detailed`][`crate::futures::FileWatchStream::detailed`].
+    /// Ignores the [`buffer`][`WatchRequest::buffer`] and
+    /// [`overflow`][`WatchRequest::overflow`] settings, as nothing is buffered between batches.
+    pub async fn watch_batches<F>(self, handler: F) -> Result<BatchWatch, WatchError>
+    where
+        F: FnMut(&[DirectoryWatchEvent]) + Send + 'static,
+    {
+        let sender = crate::task::Sender::Batch(BatchHandler::new(handler));
+        let (watch_token, path, handle, end) = self.start(sender).await?;
 
-        let sender = crate::task::Sender::Once(sender);
+        Ok(BatchWatch {
+            watch_token,
+            path,
+            handle,
+            end,
+        })
+    }
+
+    /// Send this request to the watcher task, and wait for it to be installed
+    async fn start(
+        self,
+        sender: crate::task::Sender,
+    ) -> Result<(WatchToken, Arc<Path>, Handle, Arc<OnceLock<EndReason>>), WatchError> {
+        let (path, flags, options) = self.prepare()?;
 
         let (setup_tx, setup_rx) = tokio::sync::oneshot::channel();
+        let end = Arc::<OnceLock<EndReason>>::default();
+
+        self.handle.request_tx.try_send(WatchRequestInner::Start {
+            flags,
+            path,
+            dir: T::DIR,
+            options,
+            sender,
+            end: end.clone(),
+            watch_token_tx: setup_tx,
+        })?;
+
+        let watch_token = setup_rx
+            .await
+            .map_err(|_| WatchError::WatcherShutdown)?
+            .map_err(WatchError::from)?;
+
+        Ok((watch_token, self.path.into(), self.handle.clone(), end))
+    }
+
+    /// Subscribe to an existing shared watch with exactly this path and configuration, or start
+    /// a new one if there isn't one
+    ///
+    /// The watcher task does both in a single request, so concurrent calls can't each start
+    /// their own watch.
+    async fn subscribe(self) -> Result<Subscription, WatchError> {
+        let (path, flags, options) = self.prepare()?;
+        let (sender, rx) = tokio::sync::broadcast::channel(self.config.buffer);
+        let (subscribed, subscribed_rx) = tokio::sync::oneshot::channel();
+        let (setup_tx, setup_rx) = tokio::sync::oneshot::channel();
+        let end = Arc::<OnceLock<EndReason>>::default();
 
         self.handle
             .request_tx
-            .try_send(WatchRequestInner::Start {
-                flags: self.flags,
-                path: self.path,
-                dir: false,
+            .try_send(WatchRequestInner::Subscribe {
+                path,
+                flags,
+                dir: T::DIR,
+                options,
                 sender,
+                end: end.clone(),
+                subscribed,
                 watch_token_tx: setup_tx,
+            })?;
+
+        if let Ok(subscription) = subscribed_rx.await {
+            return Ok(subscription);
+        }
+
+        let watch_token = setup_rx
+            .await
+            .map_err(|_| WatchError::WatcherShutdown)?
+            .map_err(WatchError::from)?;
+
+        Ok((watch_token, rx, end))
+    }
+
+    /// Check this request's configuration, and split out what the watcher task needs
+    fn prepare(&self) -> Result<(PathBuf, AddWatchFlags, WatchOptions), WatchError> {
+        prepare(
+            &self.path,
+            self.config.flags,
+            &self.config.options,
+            self.config.overflow,
+            T::DIR,
+        )
+    }
+}
+
+/// Check a watch's configuration, and split out what the watcher task needs
+fn prepare(
+    path: &Path,
+    mut flags: AddWatchFlags,
+    options: &WatchOptions,
+    overflow: Overflow,
+    dir: bool,
+) -> Result<(PathBuf, AddWatchFlags, WatchOptions), WatchError> {
+    let mut options = options.clone();
+    options.close_on_overflow = overflow == Overflow::ErrorClose;
+
+    validate_flags(flags, dir)?;
+    if options.only_dirs && options.only_files {
+        return Err(WatchError::ConflictingFlags);
+    }
+    if !flags.intersects(AddWatchFlags::IN_ALL_EVENTS)
+        && options.heartbeat.is_none()
+        && !options.self_events
+        && !options.write_complete
+    {
+        return Err(WatchError::NoEvents);
+    }
+
+    if !options.follow_name || options.name.is_some() {
+        return Ok((path.to_owned(), flags, options));
+    }
+
+    // Paths ending in `..` are directories, so were already rejected
+    options.name = Some(
+        path.file_name()
+            .expect("file watch path has no file name")
+            .to_string_lossy()
+            .into(),
+    );
+    flags |= AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_FROM;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+        _ => PathBuf::from("."),
+    };
+
+    Ok((parent, flags, options))
+}
+
+/// A stream watch to install before the watcher task starts, see
+/// [`Builder::pre_watch`][`crate::builder::Builder::pre_watch`]
+#[derive(Debug, Clone)]
+pub(crate) struct PreWatch {
+    path: PathBuf,
+    dir: bool,
+    buffer: usize,
+    overflow: Overflow,
+    flags: AddWatchFlags,
+    options: WatchOptions,
+}
+
+impl PreWatch {
+    /// Install this watch on `state` straight away, returning its stream
+    pub(crate) fn install(
+        self,
+        state: &mut WatcherState,
+        handle: &Handle,
+    ) -> Result<PreWatched, AnotifyError> {
+        let path = check_path(self.path, self.dir)?;
+        let (install_path, flags, options) =
+            prepare(&path, self.flags, &self.options, self.overflow, self.dir)?;
+        let (sender, inner) = stream_channel(self.buffer, self.overflow);
+        let end = Arc::<OnceLock<EndReason>>::default();
+
+        let watch_token = state
+            .install_now(install_path, flags, self.dir, options, sender, end.clone())
+            .map_err(WatchError::from)?;
+        let (backlog, path, handle) = (Default::default(), path.into(), handle.clone());
+
+        Ok(if self.dir {
+            PreWatched::Directory(DirectoryWatchStream {
+                inner,
+                backlog,
+                watch_token,
+                path,
+                handle,
+                end,
+            })
+        } else {
+            PreWatched::File(FileWatchStream {
+                inner,
+                backlog,
+                watch_token,
+                path,
+                handle,
+                end,
             })
-            .map_err(|_| WatchError::WatcherShutdown)?;
+        })
+    }
+}
 
-        let watch_token = setup_rx.await.map_err(|_| WatchError::WatcherShutdown)?;
+/// # Common Configuration Methods
+impl<T: WatchType> WatchConfig<T> {
+    common_config!();
+}
+
+/// # File Specific Configuration Methods
+impl WatchRequest<'_, FileEvents> {
+    file_config!();
+}
+
+/// # File Specific Configuration Methods
+impl WatchConfig<FileEvents> {
+    file_config!();
+}
+
+/// # Directory Specific Configuration Methods
+impl WatchRequest<'_, DirectoryEvents> {
+    directory_config!();
+}
+
+/// # Directory Specific Configuration Methods
+impl WatchConfig<DirectoryEvents> {
+    directory_config!();
+}
+
+/// Create the channel for a stream watch
+fn stream_channel(buffer: usize, overflow: Overflow) -> (crate::task::Sender, Inbox) {
+    match overflow {
+        Overflow::DropNewest | Overflow::ErrorClose => {
+            let (sender, rx) = tokio::sync::mpsc::channel(buffer);
+            (
+                crate::task::Sender::Stream(sender),
+                Inbox::Buffered(ReceiverStream::from(rx)),
+            )
+        }
+        Overflow::DropOldest => {
+            let (sender, rx) = crate::ring::channel(buffer);
+            (crate::task::Sender::Ring(sender), Inbox::Ring(rx))
+        }
+    }
+}
+
+/// # File Specific Dispatch Methods
+impl<'handle> WatchRequest<'handle, FileEvents> {
+    /// Create a watch which will only return the next captured event, and then unsubscribe
+    ///
+    /// Ignores the value set by [`buffer`][`WatchRequest::buffer`]
+    pub async fn next(self) -> Result<FileWatchFuture, WatchError> {
+        let (sender, rx) = tokio::sync::oneshot::channel();
+
+        let (watch_token, path, handle, end) =
+            self.start(crate::task::Sender::Once(sender)).await?;
 
         Ok(FileWatchFuture {
             inner: rx,
             watch_token,
-            closed: false,
-            handle: self.handle.clone(),
+            path,
+            handle,
+            end,
         })
     }
 
     /// Create a watch which will capture and return a stream of events until dropped.
     ///
-    /// Will keep oldest events on buffer overflow set by [`buffer`][`WatchRequest::buffer`]
+    /// Holds up to [`buffer`][`WatchRequest::buffer`] events, and drops events once full
+    /// according to [`overflow`][`WatchRequest::overflow`]
     pub async fn watch(self) -> Result<FileWatchStream, WatchError> {
-        let (sender, rx) = tokio::sync::mpsc::channel(self.buffer);
+        let (sender, inner) = stream_channel(self.config.buffer, self.config.overflow);
 
-        let sender = crate::task::Sender::Stream(sender);
+        let (watch_token, path, handle, end) = self.start(sender).await?;
 
-        let (setup_tx, setup_rx) = tokio::sync::oneshot::channel();
+        Ok(FileWatchStream {
+            inner,
+            backlog: Default::default(),
+            watch_token,
+            path,
+            handle,
+            end,
+        })
+    }
 
-        self.handle
-            .request_tx
-            .try_send(WatchRequestInner::Start {
-                flags: self.flags,
-                path: self.path,
-                dir: false,
-                sender,
-                watch_token_tx: setup_tx,
-            })
-            .map_err(|_| WatchError::WatcherShutdown)?;
+    /// Create a stream watch which ends itself after delivering `n` events
+    ///
+    /// The watcher task removes the watch as soon as the last event is sent, rather than once the
+    /// stream is dropped, so the inotify watch is freed promptly. The stream then ends with
+    /// [`EndReason::Taken`]. Heartbeats and errors count towards `n`. Fails with
+    /// [`WatchError::NoEvents`] if `n` is zero.
+    pub async fn watch_take(mut self, n: u64) -> Result<FileWatchStream, WatchError> {
+        if n == 0 {
+            return Err(WatchError::NoEvents);
+        }
 
-        let watch_token = setup_rx.await.map_err(|_| WatchError::WatcherShutdown)?;
+        self.config.options.limit = Some(n);
+        self.watch().await
+    }
 
-        Ok(FileWatchStream {
-            inner: ReceiverStream::from(rx),
-            watch_token,
-            handle: self.handle.clone(),
+    /// Create a stream watch which is removed once `until` completes, such as when a
+    /// cancellation token is cancelled
+    ///
+    /// Events are forwarded by a task spawned onto the current runtime, which also removes the
+    /// watch, so it is removed on time even if the returned stream is not being polled. The
+    /// returned stream ends once the watch has been removed.
+    pub async fn watch_until<F>(
+        self,
+        until: F,
+    ) -> Result<impl Stream<Item = FileWatchEvent>, WatchError>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Ok(forward_until(self.watch().await?, until))
+    }
+
+    /// Create a stream of the file's contents, which reads the whole file again each time it
+    /// changes, such as for reloading a config file
+    ///
+    /// The first item is the file as it was once the watch was installed. After that it is read
+    /// once changes have stopped for `debounce`, so a burst of writes only yields one item. It
+    /// follows the file by name like [`watch_atomic`][`WatchRequest::watch_atomic`], so files
+    /// replaced by renaming a new one over them are read too. While there is no file at the path
+    /// nothing is yielded, and other errors reading it are yielded as `Err` items after which
+    /// the stream carries on.
+    ///
+    /// The captured events are set by this method, and a write is also seen once the writer
+    /// closes the file.
+    pub async fn watch_contents(self, debounce: Duration) -> Result<ContentsStream, WatchError> {
+        let watch = self
+            .modify(true)
+            .close_write(true)
+            .errors(true)
+            .auto_close_on_self_removal(false);
+        let path = watch.path.clone();
+
+        Ok(ContentsStream::new(watch.watch().await?, path, debounce))
+    }
+
+    /// Create a stream watch which follows whichever file is at this path, rather than the file
+    /// which is there now.
+    ///
+    /// Editors and config management tools often save by writing a new file and renaming it over
+    /// the old one, which a plain [`watch`][`WatchRequest::watch`] would miss. This watches the
+    /// parent directory instead, and also reports
+    /// [`Create`][`crate::futures::FileWatchEvent::Create`] whenever a file is created or renamed
+    /// into place at this path, and [`Delete`][`crate::futures::FileWatchEvent::Delete`] whenever
+    /// it is deleted or renamed away.
+    ///
+    /// The same as a [`watch`][`WatchRequest::watch`] with
+    /// [`auto_close_on_self_removal`][`WatchRequest::auto_close_on_self_removal`] unset.
+    pub async fn watch_atomic(self) -> Result<FileWatchStream, WatchError> {
+        self.auto_close_on_self_removal(false).watch().await
+    }
+
+    /// Create a watch which can be cloned, with every clone receiving every event.
+    ///
+    /// Each clone yields
+    /// [`Lagged`][`tokio_stream::wrappers::errors::BroadcastStreamRecvError::Lagged`] if it falls
+    /// more than [`buffer`][`WatchRequest::buffer`] events behind, and then continues from the
+    /// oldest event still buffered. The watch is removed once every clone has been dropped.
+    pub async fn shared(self) -> Result<SharedFileWatchStream, WatchError> {
+        let (sender, rx) = tokio::sync::broadcast::channel(self.config.buffer);
+
+        let (watch_token, path, handle, end) =
+            self.start(crate::task::Sender::Broadcast(sender)).await?;
+
+        Ok(SharedFileWatchStream {
+            inner: BroadcastStream::new(rx.resubscribe()),
+            subscribe: rx,
+            guard: Arc::new(SharedWatchGuard {
+                watch_token,
+                path,
+                handle,
+                end,
+            }),
+        })
+    }
+
+    /// Subscribe to an existing [`shared`][`WatchRequest::shared`] watch on the same file with
+    /// the same configuration, or create a new one if there isn't one
+    ///
+    /// Paths are the same if they canonicalize to the same path, and configurations are the same
+    /// if every setting other than the buffer matches, as subscriptions share the existing
+    /// watch's buffer. Each subscription behaves as its own clone of the stream, so the watch is
+    /// removed once every subscription has been dropped.
+    pub async fn watch_dedup(mut self) -> Result<SharedFileWatchStream, WatchError> {
+        if let Ok(path) = std::fs::canonicalize(&self.path) {
+            self.path = path;
+        }
+
+        let (handle, path) = (self.handle.clone(), Arc::from(self.path.as_path()));
+        let (watch_token, rx, end) = self.subscribe().await?;
+
+        Ok(SharedFileWatchStream {
+            inner: BroadcastStream::new(rx.resubscribe()),
+            subscribe: rx,
+            guard: Arc::new(SharedWatchGuard {
+                watch_token,
+                path,
+                handle,
+                end,
+            }),
         })
     }
 }
@@ -272,58 +1558,143 @@ impl<'handle> WatchRequest<'handle, DirectoryEvents> {
     pub async fn next(self) -> Result<DirectoryWatchFuture, WatchError> {
         let (sender, rx) = tokio::sync::oneshot::channel();
 
-        let sender = crate::task::Sender::Once(sender);
-
-        let (setup_tx, setup_rx) = tokio::sync::oneshot::channel();
-
-        self.handle
-            .request_tx
-            .try_send(WatchRequestInner::Start {
-                flags: self.flags,
-                path: self.path,
-                dir: true,
-                sender,
-                watch_token_tx: setup_tx,
-            })
-            .map_err(|_| WatchError::WatcherShutdown)?;
-
-        let watch_token = setup_rx.await.map_err(|_| WatchError::WatcherShutdown)?;
+        let (watch_token, path, handle, end) =
+            self.start(crate::task::Sender::Once(sender)).await?;
 
         Ok(DirectoryWatchFuture {
             inner: rx,
             watch_token,
-            handle: self.handle.clone(),
-            closed: false,
+            path,
+            handle,
+            end,
         })
     }
 
     /// Create a watch which will capture and return a stream of events until dropped.
     ///
-    /// Will keep oldest events on buffer overflow set by [`buffer`][`WatchRequest::buffer`]
+    /// Holds up to [`buffer`][`WatchRequest::buffer`] events, and drops events once full
+    /// according to [`overflow`][`WatchRequest::overflow`]
     pub async fn watch(self) -> Result<DirectoryWatchStream, WatchError> {
-        let (sender, rx) = tokio::sync::mpsc::channel(self.buffer);
+        let (sender, inner) = stream_channel(self.config.buffer, self.config.overflow);
 
-        let sender = crate::task::Sender::Stream(sender);
+        let (watch_token, path, handle, end) = self.start(sender).await?;
 
-        let (setup_tx, setup_rx) = tokio::sync::oneshot::channel();
+        Ok(DirectoryWatchStream {
+            inner,
+            backlog: Default::default(),
+            watch_token,
+            path,
+            handle,
+            end,
+        })
+    }
 
-        self.handle
-            .request_tx
-            .try_send(WatchRequestInner::Start {
-                flags: self.flags,
-                path: self.path,
-                dir: true,
-                sender,
-                watch_token_tx: setup_tx,
-            })
-            .map_err(|_| WatchError::WatcherShutdown)?;
+    /// Create a stream of the directory's entries, which yields every entry after each change
+    ///
+    /// The first item lists the directory as it was once the watch was installed, and the rest
+    /// follow from applying creates, deletes, and moves to it. Because the listing is taken after
+    /// the watch is installed, changes made while listing are applied again afterwards, which
+    /// leaves the same set of entries. The directory is listed again if inotify's queue
+    /// overflows.
+    ///
+    /// Entry filters such as [`only_dirs`][`WatchRequest::only_dirs`] apply to the listing too,
+    /// while the captured events and path style are set by this method. Events dropped once the
+    /// [`buffer`][`WatchRequest::buffer`] is full are not noticed, so it should be large enough
+    /// for the busiest bursts in the directory.
+    pub async fn watch_state(self) -> Result<DirectoryStateStream, WatchError> {
+        let watch = self
+            .create(true)
+            .delete(true)
+            .moves(true)
+            .errors(true)
+            .path_style(PathStyle::Bare);
+        let path = watch.path.clone();
+        let options = watch.config.options.clone();
 
-        let watch_token = setup_rx.await.map_err(|_| WatchError::WatcherShutdown)?;
+        DirectoryStateStream::new(watch.watch().await?, path, options)
+            .await
+            .map_err(WatchError::List)
+    }
 
-        Ok(DirectoryWatchStream {
-            inner: ReceiverStream::from(rx),
-            watch_token,
-            handle: self.handle.clone(),
+    /// Create a stream watch which ends itself after delivering `n` events
+    ///
+    /// The watcher task removes the watch as soon as the last event is sent, rather than once the
+    /// stream is dropped, so the inotify watch is freed promptly. The stream then ends with
+    /// [`EndReason::Taken`]. Heartbeats and errors count towards `n`. Fails with
+    /// [`WatchError::NoEvents`] if `n` is zero.
+    pub async fn watch_take(mut self, n: u64) -> Result<DirectoryWatchStream, WatchError> {
+        if n == 0 {
+            return Err(WatchError::NoEvents);
+        }
+
+        self.config.options.limit = Some(n);
+        self.watch().await
+    }
+
+    /// Create a stream watch which is removed once `until` completes, such as when a
+    /// cancellation token is cancelled
+    ///
+    /// Events are forwarded by a task spawned onto the current runtime, which also removes the
+    /// watch, so it is removed on time even if the returned stream is not being polled. The
+    /// returned stream ends once the watch has been removed.
+    pub async fn watch_until<F>(
+        self,
+        until: F,
+    ) -> Result<impl Stream<Item = DirectoryWatchEvent>, WatchError>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Ok(forward_until(self.watch().await?, until))
+    }
+
+    /// Create a watch which can be cloned, with every clone receiving every event.
+    ///
+    /// Each clone yields
+    /// [`Lagged`][`tokio_stream::wrappers::errors::BroadcastStreamRecvError::Lagged`] if it falls
+    /// more than [`buffer`][`WatchRequest::buffer`] events behind, and then continues from the
+    /// oldest event still buffered. The watch is removed once every clone has been dropped.
+    pub async fn shared(self) -> Result<SharedDirectoryWatchStream, WatchError> {
+        let (sender, rx) = tokio::sync::broadcast::channel(self.config.buffer);
+
+        let (watch_token, path, handle, end) =
+            self.start(crate::task::Sender::Broadcast(sender)).await?;
+
+        Ok(SharedDirectoryWatchStream {
+            inner: BroadcastStream::new(rx.resubscribe()),
+            subscribe: rx,
+            guard: Arc::new(SharedWatchGuard {
+                watch_token,
+                path,
+                handle,
+                end,
+            }),
+        })
+    }
+
+    /// Subscribe to an existing [`shared`][`WatchRequest::shared`] watch on the same directory with
+    /// the same configuration, or create a new one if there isn't one
+    ///
+    /// Paths are the same if they canonicalize to the same path, and configurations are the same
+    /// if every setting other than the buffer matches, as subscriptions share the existing
+    /// watch's buffer. Each subscription behaves as its own clone of the stream, so the watch is
+    /// removed once every subscription has been dropped.
+    pub async fn watch_dedup(mut self) -> Result<SharedDirectoryWatchStream, WatchError> {
+        if let Ok(path) = std::fs::canonicalize(&self.path) {
+            self.path = path;
+        }
+
+        let (handle, path) = (self.handle.clone(), Arc::from(self.path.as_path()));
+        let (watch_token, rx, end) = self.subscribe().await?;
+
+        Ok(SharedDirectoryWatchStream {
+            inner: BroadcastStream::new(rx.resubscribe()),
+            subscribe: rx,
+            guard: Arc::new(SharedWatchGuard {
+                watch_token,
+                path,
+                handle,
+                end,
+            }),
         })
     }
 }
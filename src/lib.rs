@@ -6,50 +6,87 @@ extern crate thiserror;
 extern crate tokio;
 extern crate tokio_stream;
 
+use builder::Builder;
 use error::InitError;
-use handle::{Handle, OwnedHandle};
+use handle::OwnedHandle;
 
+pub mod builder;
 pub mod futures;
 pub mod handle;
+mod ring;
+pub mod sink;
+pub mod snapshot;
+pub mod stats;
 mod task;
 #[macro_use]
 mod tracing;
 pub mod error;
 
-// TODO(josiah) convert this to a builder style to allow for request buffer configurations, as well
-// as max watchers
+/// Create an anotify instance with the default configuration
+///
+/// Must be called from within a tokio runtime, see [`InitError::NoRuntime`].
 pub fn new() -> Result<OwnedHandle, InitError> {
-    let (request_tx, request_rx) = tokio::sync::mpsc::channel(OwnedHandle::DEFAULT_REQUEST_BUFFER);
-    let inner = Handle { request_tx };
-    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
-
-    let join = task::WatcherState::launch(Box::new(task::WatcherState::new(
-        request_rx,
-        shutdown_rx,
-        None,
-    )?));
-
-    Ok(OwnedHandle {
-        inner,
-        join,
-        shutdown: shutdown_tx,
-    })
+    builder().build_current()
+}
+
+/// Create an anotify instance which runs on its own thread and runtime
+///
+/// Unlike [`new`], this may be called from outside of a tokio runtime, such as from a plain `main`.
+/// The runtime is shut down along with the watcher task, once the returned handle is dropped or
+/// shut down. See [`dedicated_thread`][`Builder::dedicated_thread`].
+pub fn with_new_runtime() -> Result<OwnedHandle, InitError> {
+    builder().dedicated_thread(true).build_current()
+}
+
+/// Configure a new anotify instance
+pub fn builder() -> Builder {
+    Builder::default()
 }
 
 #[cfg(test)]
 mod test {
-    use std::{future::Future, io::Write, path::PathBuf, time::Duration};
+    use std::{
+        collections::BTreeMap,
+        future::Future,
+        io::Write,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
 
     use tempdir::TempDir;
     use tokio::{test, time::Timeout};
     use tokio_stream::StreamExt;
 
-    use crate::futures::FileWatchEvent;
+    use nix::{errno::Errno, sys::inotify::AddWatchFlags};
+
+    use crate::{
+        builder::TimestampMode,
+        error::{AnotifyError, InitError},
+        futures::{
+            DirEntry, DirectoryWatchStream, EndReason, EventError, FileWatchEvent, Timestamp,
+            WatchKind,
+        },
+        handle::{
+            DirectoryEvents, FileEvents, Handle, Once, Overflow, PathStyle, RequestError,
+            ResourceLimit, ShutdownReport, WatchConfig, WatchError,
+        },
+        sink::LogFormat,
+        task::{WatchRequestInner, WatcherState},
+    };
 
     fn setup_testdir() -> TempDir {
         TempDir::new("testdir").unwrap()
     }
 
+    /// Weather the inotify instance open as `fd` has a watch on `path`, read from its fdinfo
+    fn is_watched_by(fd: std::os::fd::RawFd, path: &std::path::Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        let ino = format!("ino:{:x} ", std::fs::metadata(path).unwrap().ino());
+        std::fs::read_to_string(format!("/proc/self/fdinfo/{fd}"))
+            .is_ok_and(|info| info.lines().any(|line| line.contains(&ino)))
+    }
+
     struct TestFile(PathBuf, usize);
 
     fn timeout<F: Future>(fut: F) -> Timeout<F> {
@@ -116,6 +153,34 @@ mod test {
         owner.shutdown().await;
     }
 
+    #[test]
+    async fn shutdown_with_report() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        let _dir = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+        let _file = owner.file(&file_path).unwrap().modify(true).watch().await;
+        let _other = owner.file(&file_path).unwrap().open(true).next().await;
+
+        let report = owner.shutdown_with_report(Duration::from_secs(2)).await;
+        assert_eq!(
+            report,
+            ShutdownReport {
+                clean: true,
+                watches: 2,
+                watchers: 3,
+            }
+        );
+    }
+
     #[test]
     async fn stream_file() {
         let mut owner = crate::new().unwrap();
@@ -164,7 +229,7 @@ mod test {
         let mut f2 = TestFile::new(fp2.clone());
 
         let mut stream = owner
-            .dir(test_dir.path().into())
+            .dir(test_dir.path())
             .unwrap()
             .modify(true)
             .watch()
@@ -200,4 +265,3541 @@ mod test {
         assert!(got_1);
         assert!(got_2);
     }
+
+    #[test]
+    async fn raw_mask() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut file = TestFile::new(test_dir.path().join("test.txt"));
+
+        let fut = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .close(true)
+            .raw_mask(true)
+            .next()
+            .await
+            .unwrap();
+
+        file.change();
+
+        let event = timeout(fut).await.unwrap().unwrap();
+
+        assert_eq!(event.event, FileWatchEvent::Close { writable: true });
+        assert_eq!(event.raw_mask, Some(AddWatchFlags::IN_CLOSE_WRITE.bits()));
+        assert_eq!(event.raw_flags(), Some(AddWatchFlags::IN_CLOSE_WRITE));
+    }
+
+    #[test]
+    async fn file_at() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut file = TestFile::new(test_dir.path().join("test.txt"));
+        let dir = std::fs::File::open(test_dir.path()).unwrap();
+
+        let fut = owner
+            .file_at(&dir, "test.txt")
+            .unwrap()
+            .modify(true)
+            .next()
+            .await
+            .unwrap();
+
+        file.change();
+
+        assert_eq!(timeout(fut).await.unwrap(), Some(FileWatchEvent::Write));
+    }
+
+    #[test]
+    async fn batch_stats() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut f1 = TestFile::new(test_dir.path().join("test1.txt"));
+        let mut f2 = TestFile::new(test_dir.path().join("test2.txt"));
+
+        let _stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        f1.change();
+        f2.change();
+        wait().await;
+
+        let stats = owner.stats().await.unwrap();
+
+        assert!(stats.events >= 2);
+        assert!(stats.batches.count >= 1);
+        assert!(stats.batches.min <= stats.batches.max);
+        assert_eq!(stats.batches.total, stats.events);
+    }
+
+    #[test]
+    async fn drop_with_full_requests() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut streams = Vec::new();
+        for i in 0..8 {
+            let path = test_dir.path().join(format!("test{i}.txt"));
+            TestFile::new(path.clone());
+
+            streams.push(
+                owner
+                    .file(path)
+                    .unwrap()
+                    .modify(true)
+                    .watch()
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        assert_eq!(owner.stats().await.unwrap().watches, 8);
+
+        // The task cannot run until we yield, so fill the request channel ahead of the drops
+        let mut pending = Vec::new();
+        loop {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            if owner
+                .request_tx
+                .try_send(WatchRequestInner::Stats(tx))
+                .is_err()
+            {
+                break;
+            }
+            pending.push(rx);
+        }
+
+        drop(streams);
+
+        for rx in pending {
+            rx.await.unwrap();
+        }
+
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+    }
+
+    #[test]
+    async fn shutdown_with_full_requests() {
+        let owner = crate::new().unwrap();
+
+        let mut pending = Vec::new();
+        loop {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            if owner
+                .request_tx
+                .try_send(WatchRequestInner::Stats(tx))
+                .is_err()
+            {
+                break;
+            }
+            pending.push(rx);
+        }
+
+        tokio::time::timeout(
+            Duration::from_millis(500),
+            owner.shutdown_with(Duration::from_secs(5)),
+        )
+        .await
+        .expect("Shutdown was delayed by queued requests");
+
+        // Requests which were already queued are still answered
+        for rx in pending {
+            assert!(
+                rx.await.is_ok(),
+                "Request queued before shutdown was dropped"
+            );
+        }
+    }
+
+    #[test]
+    async fn raw_flags() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        let result = owner
+            .file(file_path)
+            .unwrap()
+            .raw_flags(AddWatchFlags::IN_ONLYDIR)
+            .watch()
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(WatchError::InvalidFlags(AddWatchFlags::IN_ONLYDIR))
+        ));
+
+        owner
+            .dir(test_dir.path())
+            .unwrap()
+            .raw_flags(AddWatchFlags::IN_ONLYDIR | AddWatchFlags::IN_ATTRIB)
+            .watch()
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    async fn pending_requests() {
+        let owner = crate::new().unwrap();
+
+        assert_eq!(owner.pending_requests(), 0);
+
+        // The task cannot run until we yield, so these stay queued
+        let pending: Vec<_> = (0..3)
+            .map(|_| {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                owner
+                    .request_tx
+                    .try_send(WatchRequestInner::Stats(tx))
+                    .unwrap();
+                rx
+            })
+            .collect();
+
+        assert_eq!(owner.pending_requests(), 3);
+
+        for rx in pending {
+            rx.await.unwrap();
+        }
+
+        assert_eq!(owner.pending_requests(), 0);
+    }
+
+    #[test(flavor = "multi_thread")]
+    async fn dedicated_thread() {
+        let mut owner = crate::builder()
+            .dedicated_thread(true)
+            .name("anotify-test")
+            .build_current()
+            .unwrap();
+
+        let threads = std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .map(|task| std::fs::read_to_string(task.unwrap().path().join("comm")).unwrap())
+            .collect::<Vec<_>>();
+        assert!(threads.iter().any(|comm| comm.trim_end() == "anotify-test"));
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let fut = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .next()
+            .await
+            .unwrap();
+
+        file.change();
+
+        assert_eq!(timeout(fut).await.unwrap(), Some(FileWatchEvent::Write));
+
+        owner.shutdown().await;
+    }
+
+    #[test]
+    async fn child_kind() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let dir_path = test_dir.path().join("subdir");
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .delete(true)
+            .watch()
+            .await
+            .unwrap();
+
+        TestFile::new(file_path.clone());
+        std::fs::create_dir(&dir_path).unwrap();
+        std::fs::remove_file(&file_path).unwrap();
+        std::fs::remove_dir(&dir_path).unwrap();
+
+        let mut events = Vec::new();
+        while let Ok(Some(item)) = timeout(stream.next()).await {
+            events.push((item.inner_path.unwrap(), item.event, item.is_dir));
+        }
+
+        assert_eq!(
+            events,
+            [
+                ("test.txt".into(), FileWatchEvent::Create, false),
+                ("subdir".into(), FileWatchEvent::Create, true),
+                ("test.txt".into(), FileWatchEvent::Delete, false),
+                ("subdir".into(), FileWatchEvent::Delete, true),
+            ]
+        );
+    }
+
+    #[test]
+    async fn pseudo_fs() {
+        let mut owner = crate::new().unwrap();
+
+        assert!(matches!(
+            owner.file("/proc/meminfo"),
+            Err(RequestError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    async fn request_buffer_full() {
+        let owner = crate::builder().request_buffer(1).build_current().unwrap();
+
+        let (tx, _rx) = tokio::sync::oneshot::channel();
+        owner
+            .request_tx
+            .try_send(WatchRequestInner::Stats(tx))
+            .unwrap();
+
+        assert!(matches!(
+            owner.stats().await,
+            Err(WatchError::RequestBufferFull)
+        ));
+    }
+
+    #[test]
+    async fn per_path_order() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut f1 = TestFile::new(test_dir.path().join("test1.txt"));
+        let mut f2 = TestFile::new(test_dir.path().join("test2.txt"));
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .modify(true)
+            .close(true)
+            .buffer(64)
+            .watch()
+            .await
+            .unwrap();
+
+        f1.change();
+        f2.change();
+        f1.change();
+
+        let mut test1 = Vec::new();
+        let mut test2 = Vec::new();
+        while let Ok(Some(item)) = timeout(stream.next()).await {
+            match item.inner_path.as_deref() {
+                Some("test1.txt") => test1.push(item.event),
+                Some("test2.txt") => test2.push(item.event),
+                _ => panic!("Did not expect {item:#?}"),
+            }
+        }
+
+        // The kernel may merge consecutive identical events, so only the ordering is checked
+        test1.dedup();
+        test2.dedup();
+
+        let write = FileWatchEvent::Write;
+        let close = FileWatchEvent::Close { writable: true };
+        assert_eq!(test1, [write, close, write, close]);
+        assert_eq!(test2, [write, close]);
+    }
+
+    #[test]
+    async fn per_path_order_moves() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let (a, b) = (test_dir.path().join("a"), test_dir.path().join("b"));
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .modify(true)
+            .close(true)
+            .moves(true)
+            .buffer(64)
+            .watch()
+            .await
+            .unwrap();
+
+        // Pairing each rename must not move it past the writes on either side of it
+        std::fs::write(&a, "first").unwrap();
+        std::fs::rename(&a, &b).unwrap();
+        std::fs::write(&b, "second").unwrap();
+        std::fs::rename(&b, &a).unwrap();
+        std::fs::write(&a, "third").unwrap();
+
+        let mut events = Vec::new();
+        while let Ok(Some(item)) = timeout(stream.next()).await {
+            events.push((item.inner_path, item.event, item.moved_to));
+        }
+        events.dedup();
+
+        let name = |name: &str| Some(std::sync::Arc::<str>::from(name));
+        let write = FileWatchEvent::Write;
+        let close = FileWatchEvent::Close { writable: true };
+        let moved = FileWatchEvent::Moved;
+        assert_eq!(
+            events,
+            [
+                (name("a"), write, None),
+                (name("a"), close, None),
+                (name("a"), moved, name("b")),
+                (name("b"), write, None),
+                (name("b"), close, None),
+                (name("b"), moved, name("a")),
+                (name("a"), write, None),
+                (name("a"), close, None),
+            ]
+        );
+    }
+
+    #[test]
+    async fn dump() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        let _stream = owner
+            .file(file_path.clone())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let _fut = owner
+            .file(file_path.clone())
+            .unwrap()
+            .open(true)
+            .next()
+            .await
+            .unwrap();
+
+        let snapshot = owner.dump().await.unwrap();
+
+        assert_eq!(snapshot.watches.len(), 1);
+        let watch = &snapshot.watches[0];
+        assert_eq!(watch.path, file_path);
+        assert_eq!(watch.watchers.len(), 2);
+        assert!(!watch.watchers[0].once);
+        assert!(watch.watchers[1].once);
+        assert_eq!(watch.watchers[1].flags, AddWatchFlags::IN_OPEN.bits());
+    }
+
+    #[test]
+    async fn describe() {
+        let flags = AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_ONLYDIR;
+
+        assert_eq!(
+            crate::futures::describe(flags),
+            "Write, Close (modified), Create, (only directories)"
+        );
+        assert_eq!(crate::futures::describe(AddWatchFlags::empty()), "");
+    }
+
+    #[test]
+    async fn resize_buffer() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .open(true)
+            .buffer(1)
+            .watch()
+            .await
+            .unwrap();
+
+        // Only the first event fits in the buffer
+        file.change();
+        wait().await;
+
+        stream.resize_buffer(8).await.unwrap();
+
+        file.change();
+        wait().await;
+
+        let mut events = Vec::new();
+        while let Ok(Some(item)) = timeout(stream.next()).await {
+            events.push(item);
+        }
+
+        assert_eq!(
+            events,
+            [
+                FileWatchEvent::Open,
+                FileWatchEvent::Open,
+                FileWatchEvent::Write
+            ]
+        );
+    }
+
+    #[test]
+    async fn resize_buffer_removed() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        // Zero is treated as one rather than panicking
+        stream.resize_buffer(0).await.unwrap();
+
+        file.change();
+        wait().await;
+        owner.unwatch_prefix(test_dir.path()).await.unwrap();
+
+        assert!(matches!(
+            stream.resize_buffer(8).await,
+            Err(WatchError::Closed)
+        ));
+        // The stream still drains what it had buffered before it ends
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(timeout(stream.next()).await.unwrap(), None);
+    }
+
+    #[test]
+    async fn rebind() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        owner.rebind(runtime.handle().clone()).await.unwrap();
+        assert_eq!(runtime.metrics().num_alive_tasks(), 1);
+
+        file.change();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+
+        owner.shutdown().await;
+        runtime.shutdown_background();
+    }
+
+    #[::std::prelude::v1::test]
+    fn rebind_after_runtime_dropped() {
+        let runtime = || {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()
+                .unwrap()
+        };
+        let test_dir = setup_testdir();
+
+        let first = runtime();
+        let (mut owner, mut stream) = first.block_on(async {
+            let mut owner = crate::new().unwrap();
+            let stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch()
+                .await
+                .unwrap();
+
+            (owner, stream)
+        });
+        // Takes the task with it
+        drop(first);
+
+        // Made while there is no task, and read once there is one again
+        let _before = TestFile::new(test_dir.path().join("before.txt"));
+
+        let second = runtime();
+        second.block_on(async {
+            owner
+                .rebind(tokio::runtime::Handle::current())
+                .await
+                .unwrap();
+
+            let _after = TestFile::new(test_dir.path().join("after.txt"));
+            for name in ["before.txt", "after.txt"] {
+                let event = timeout(stream.next()).await.unwrap().unwrap();
+                assert_eq!(event.inner_path.as_deref(), Some(name));
+            }
+
+            owner.shutdown().await;
+        });
+    }
+
+    #[test]
+    async fn expire_after() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .expire_after(Duration::from_millis(500))
+            .watch()
+            .await
+            .unwrap();
+
+        file.change();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(timeout(stream.next()).await.unwrap(), None);
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+    }
+
+    #[test]
+    async fn hardlinks() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let link_path = test_dir.path().join("link.txt");
+        let mut file = TestFile::new(file_path.clone());
+        std::fs::hard_link(&file_path, &link_path).unwrap();
+
+        let mut modify = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut close = owner
+            .file(link_path)
+            .unwrap()
+            .close(true)
+            .watch()
+            .await
+            .unwrap();
+
+        let snapshot = owner.dump().await.unwrap();
+        assert_eq!(snapshot.watches.len(), 1);
+        assert_eq!(snapshot.watches[0].watchers.len(), 2);
+
+        file.change();
+
+        assert_eq!(
+            timeout(modify.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(
+            timeout(close.next()).await.unwrap(),
+            Some(FileWatchEvent::Close { writable: true })
+        );
+    }
+
+    #[test]
+    async fn filter_kind() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut file = TestFile::new(test_dir.path().join("test.txt"));
+
+        let stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .modify(true)
+            .open(true)
+            .close(true)
+            .watch()
+            .await
+            .unwrap()
+            .filter_kind(FileWatchEvent::Write);
+        tokio::pin!(stream);
+
+        file.change();
+        wait().await;
+        file.change();
+
+        let mut count = 0;
+        while let Ok(Some(item)) = timeout(stream.next()).await {
+            assert_eq!(item.event, FileWatchEvent::Write);
+            count += 1;
+        }
+
+        assert!(count >= 2);
+    }
+
+    #[test]
+    async fn conflicting_flags() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let result = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .only_dirs(true)
+            .only_files(true)
+            .watch()
+            .await;
+
+        assert!(matches!(result, Err(WatchError::ConflictingFlags)));
+    }
+
+    #[test]
+    async fn no_events() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let result = owner.dir(test_dir.path()).unwrap().watch().await;
+        assert!(matches!(result, Err(WatchError::NoEvents)));
+
+        // Flags which only constrain the watch do not count as events
+        let result = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .raw_flags(AddWatchFlags::IN_ONLYDIR | AddWatchFlags::IN_DONT_FOLLOW)
+            .next()
+            .await;
+        assert!(matches!(result, Err(WatchError::NoEvents)));
+
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+    }
+
+    #[test]
+    async fn add_interest() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path.clone())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        stream.add_interest(AddWatchFlags::IN_ACCESS).await.unwrap();
+
+        file.change();
+        wait().await;
+        std::fs::read(&file_path).unwrap();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Read)
+        );
+    }
+
+    #[test]
+    async fn add_interest_replaced() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let _file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path.clone())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        // Keep the watched inode alive, so the watch isn't removed when it's replaced
+        std::fs::hard_link(&file_path, test_dir.path().join("link.txt")).unwrap();
+        let replacement = TestFile::new(test_dir.path().join("new.txt"));
+        std::fs::rename(&replacement.0, &file_path).unwrap();
+
+        assert!(matches!(
+            stream.add_interest(AddWatchFlags::IN_ACCESS).await,
+            Err(WatchError::Stale)
+        ));
+        assert_eq!(owner.dump().await.unwrap().watches.len(), 1);
+    }
+
+    #[test]
+    async fn shared_stream() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut first = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .shared()
+            .await
+            .unwrap();
+        let mut second = first.clone();
+
+        file.change();
+
+        assert_eq!(
+            timeout(first.next()).await.unwrap().unwrap().unwrap(),
+            FileWatchEvent::Write
+        );
+        assert_eq!(
+            timeout(second.next()).await.unwrap().unwrap().unwrap(),
+            FileWatchEvent::Write
+        );
+
+        drop(first);
+        assert_eq!(owner.stats().await.unwrap().watches, 1);
+
+        drop(second);
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+    }
+
+    #[test]
+    async fn many_watchers() {
+        const WATCHERS: usize = 64;
+        const FILES: usize = 16;
+
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut streams = Vec::with_capacity(WATCHERS);
+        for _ in 0..WATCHERS {
+            streams.push(
+                owner
+                    .dir(test_dir.path())
+                    .unwrap()
+                    .create(true)
+                    .buffer(FILES)
+                    .watch()
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        for i in 0..FILES {
+            TestFile::new(test_dir.path().join(format!("{i}.txt")));
+        }
+
+        let mut first = None;
+        for stream in streams.iter_mut() {
+            for i in 0..FILES {
+                let event = timeout(stream.next()).await.unwrap().unwrap();
+                assert_eq!(event.event, FileWatchEvent::Create);
+                assert_eq!(
+                    event.inner_path.as_deref(),
+                    Some(format!("{i}.txt").as_str())
+                );
+
+                if i == 0 {
+                    let path = event.inner_path.unwrap();
+                    // Every watcher should share the same allocation for the path
+                    assert!(first.get_or_insert_with(|| path.clone()).as_ptr() == path.as_ptr());
+                }
+            }
+        }
+
+        assert_eq!(owner.stats().await.unwrap().dropped, 0);
+    }
+
+    #[test]
+    async fn fatal_error() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        let mut file = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut dir = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+
+        assert_eq!(file.error(), None);
+
+        owner
+            .request_tx
+            .try_send(WatchRequestInner::Fail(Errno::EIO))
+            .unwrap();
+
+        // Every stream gets the cause as its last event, even without `errors`
+        assert_eq!(
+            timeout(file.next()).await.unwrap(),
+            Some(FileWatchEvent::Error(EventError::Fatal(Errno::EIO)))
+        );
+        assert_eq!(timeout(file.next()).await.unwrap(), None);
+        assert_eq!(file.error(), Some(Errno::EIO));
+        assert_eq!(file.end_reason(), Some(EndReason::Failed(Errno::EIO)));
+
+        let event = timeout(dir.next()).await.unwrap().unwrap();
+        assert_eq!(
+            event.event,
+            FileWatchEvent::Error(EventError::Fatal(Errno::EIO))
+        );
+        assert_eq!(timeout(dir.next()).await.unwrap(), None);
+        assert_eq!(dir.error(), Some(Errno::EIO));
+        assert_eq!(dir.end_reason(), Some(EndReason::Failed(Errno::EIO)));
+        assert_eq!(owner.error(), Some(Errno::EIO));
+    }
+
+    #[test]
+    async fn recoverable_error() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut results = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .errors(true)
+            .watch()
+            .await
+            .unwrap()
+            .results();
+        let mut quiet = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+
+        owner
+            .request_tx
+            .try_send(WatchRequestInner::Recoverable(EventError::QueueOverflow))
+            .unwrap();
+        assert_eq!(
+            timeout(results.next()).await.unwrap(),
+            Some(Err(EventError::QueueOverflow))
+        );
+
+        // Both watches carry on, and only the one which asked for errors saw it
+        TestFile::new(test_dir.path().join("test.txt"));
+
+        let event = timeout(results.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(event.event, FileWatchEvent::Create);
+        let event = timeout(quiet.next()).await.unwrap().unwrap();
+        assert_eq!(event.event, FileWatchEvent::Create);
+        assert_eq!(owner.error(), None);
+    }
+
+    #[test]
+    async fn overflow() {
+        let mut owner = crate::new().unwrap();
+
+        for (overflow, expected, delivered) in [
+            (Overflow::DropNewest, "a", 1),
+            (Overflow::DropOldest, "c", 3),
+        ] {
+            let test_dir = setup_testdir();
+
+            let mut stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .buffer(1)
+                .overflow(overflow)
+                .watch()
+                .await
+                .unwrap();
+
+            for name in ["a", "b", "c"] {
+                TestFile::new(test_dir.path().join(name));
+            }
+
+            // Let every event arrive before reading any of them
+            wait().await;
+
+            let event = timeout(stream.next()).await.unwrap().unwrap();
+            assert_eq!(event.inner_path.as_deref(), Some(expected), "{overflow:?}");
+            assert!(timeout(stream.next()).await.is_err(), "{overflow:?}");
+
+            let snapshot = owner.dump().await.unwrap();
+            let watch = snapshot
+                .watches
+                .iter()
+                .find(|watch| watch.path == test_dir.path())
+                .unwrap();
+            assert_eq!(watch.watchers[0].delivered, delivered, "{overflow:?}");
+            assert_eq!(watch.watchers[0].dropped, 2, "{overflow:?}");
+        }
+
+        assert_eq!(owner.stats().await.unwrap().dropped, 4);
+    }
+
+    #[test]
+    async fn overflow_error_close() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .buffer(1)
+            .overflow(Overflow::ErrorClose)
+            .watch()
+            .await
+            .unwrap();
+
+        for name in ["a", "b", "c"] {
+            TestFile::new(test_dir.path().join(name));
+        }
+        wait().await;
+
+        // What was buffered before the overflow is still delivered, and then the stream ends
+        let event = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(event.inner_path.as_deref(), Some("a"));
+        assert_eq!(timeout(stream.next()).await.unwrap(), None);
+        assert_eq!(stream.end_reason(), Some(EndReason::Overflowed));
+    }
+
+    #[test]
+    async fn watch_kind() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let file_stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let dir_stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut merged = file_stream.detailed().merge(dir_stream);
+
+        file.change();
+
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let event = timeout(merged.next()).await.unwrap().unwrap();
+            events.push((event.watch_kind, event.inner_path));
+        }
+        events.sort_by_key(|(kind, _)| *kind == WatchKind::Directory);
+
+        assert_eq!(
+            events,
+            [
+                (WatchKind::File, None),
+                (WatchKind::Directory, Some("test.txt".into()))
+            ]
+        );
+    }
+
+    #[test]
+    async fn rewatch() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut first = TestFile::new(test_dir.path().join("first.txt"));
+        let mut second = TestFile::new(test_dir.path().join("second.txt"));
+
+        let mut stream = owner
+            .file(test_dir.path().join("first.txt"))
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        first.change();
+        wait().await;
+
+        stream
+            .rewatch(test_dir.path().join("second.txt"))
+            .await
+            .unwrap();
+
+        // Buffered from before the move
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Write
+        );
+
+        first.change();
+        second.change();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Write
+        );
+        assert!(timeout(stream.next()).await.is_err());
+
+        let dump = owner.dump().await.unwrap();
+        assert_eq!(dump.watches.len(), 1);
+        assert_eq!(dump.watches[0].path, test_dir.path().join("second.txt"));
+
+        assert!(matches!(
+            stream.rewatch(test_dir.path()).await,
+            Err(crate::error::AnotifyError::Request(
+                RequestError::IncorrectType(_)
+            ))
+        ));
+    }
+
+    #[test]
+    async fn watch_atomic() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let config_path = test_dir.path().join("config.toml");
+        let mut config = TestFile::new(config_path.clone());
+
+        let mut stream = owner
+            .file(config_path.clone())
+            .unwrap()
+            .modify(true)
+            .watch_atomic()
+            .await
+            .unwrap();
+
+        config.change();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Write
+        );
+
+        // Save the way editors do, by renaming a new file over the old one
+        let temp_path = test_dir.path().join("config.toml.tmp");
+        std::fs::write(&temp_path, "new contents").unwrap();
+        std::fs::rename(&temp_path, &config_path).unwrap();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Create
+        );
+
+        // Only events for the file at the watched path are delivered
+        std::fs::write(&temp_path, "unrelated").unwrap();
+        std::fs::write(&config_path, "newer contents").unwrap();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Write
+        );
+
+        // Renaming the file away or deleting it are both reported as the file being deleted
+        std::fs::rename(&config_path, &temp_path).unwrap();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Delete
+        );
+
+        std::fs::write(&config_path, "").unwrap();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Create
+        );
+        std::fs::remove_file(&config_path).unwrap();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Delete
+        );
+    }
+
+    #[test]
+    async fn read_calls() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let _stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .buffer(64)
+            .watch()
+            .await
+            .unwrap();
+
+        let before = owner.stats().await.unwrap();
+        assert_eq!(before.read_calls, 0);
+        assert_eq!(before.events_per_read(), None);
+
+        for i in 0..32 {
+            TestFile::new(test_dir.path().join(format!("test{i}.txt")));
+        }
+        wait().await;
+
+        let after = owner.stats().await.unwrap();
+        let average = after.events_per_read().unwrap();
+
+        assert!(after.read_calls > 0);
+        assert!(after.read_calls <= after.events);
+        assert!((1.0..=after.events as f64).contains(&average));
+    }
+
+    #[test]
+    async fn event_kinds() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let _stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .modify(true)
+            .buffer(64)
+            .watch()
+            .await
+            .unwrap();
+
+        let mut files = (0..3)
+            .map(|i| TestFile::new(test_dir.path().join(format!("test{i}.txt"))))
+            .collect::<Vec<_>>();
+        files[0].change();
+        wait().await;
+
+        let kinds = owner.stats().await.unwrap().kinds;
+        assert_eq!(kinds.create, 3);
+        assert!(kinds.write >= 1);
+        assert_eq!(
+            (kinds.read, kinds.open, kinds.close, kinds.delete),
+            (0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    async fn unwatch_prefix() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let plugin = test_dir.path().join("plugin");
+        let sibling = test_dir.path().join("plugin-data");
+        std::fs::create_dir(&plugin).unwrap();
+        std::fs::create_dir(&sibling).unwrap();
+        let mut file = TestFile::new(plugin.join("test.txt"));
+
+        let mut dir_stream = owner
+            .dir(&plugin)
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut file_stream = owner
+            .file(plugin.join("test.txt"))
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let _sibling_stream = owner
+            .dir(&sibling)
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+        let _parent_stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+
+        let snapshot = owner.dump().await.unwrap();
+        let mut under = snapshot
+            .under(&plugin)
+            .map(|watch| watch.path.clone())
+            .collect::<Vec<_>>();
+        under.sort();
+        assert_eq!(under, [plugin.clone(), plugin.join("test.txt")]);
+
+        assert_eq!(owner.unwatch_prefix(&plugin).await.unwrap(), 2);
+
+        file.change();
+
+        assert_eq!(timeout(dir_stream.next()).await.unwrap(), None);
+        assert_eq!(timeout(file_stream.next()).await.unwrap(), None);
+
+        let mut paths = owner
+            .dump()
+            .await
+            .unwrap()
+            .watches
+            .into_iter()
+            .map(|watch| watch.path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, [test_dir.path().to_owned(), sibling]);
+    }
+
+    #[test]
+    async fn watch_config() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let config = WatchConfig::<FileEvents>::new().modify(true).buffer(4);
+
+        let mut files = Vec::new();
+        let mut streams = Vec::new();
+        for i in 0..3 {
+            let path = test_dir.path().join(format!("test{i}.txt"));
+            files.push(TestFile::new(path.clone()));
+            streams.push(owner.apply(&config, path).unwrap().watch().await.unwrap());
+        }
+
+        for file in files.iter_mut() {
+            file.change();
+        }
+
+        for stream in streams.iter_mut() {
+            assert_eq!(
+                timeout(stream.next()).await.unwrap().unwrap(),
+                FileWatchEvent::Write
+            );
+        }
+
+        assert!(matches!(
+            owner.apply(&config, test_dir.path()),
+            Err(RequestError::IncorrectType(_))
+        ));
+    }
+
+    #[test(start_paused = true)]
+    async fn heartbeat() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+        let interval = Duration::from_secs(1);
+
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .heartbeat(interval)
+            .watch()
+            .await
+            .unwrap();
+        let start = tokio::time::Instant::now();
+
+        // Heartbeats keep their cadence on a quiet watch
+        for beat in 1..=3 {
+            assert_eq!(
+                timeout(stream.next()).await.unwrap().unwrap(),
+                FileWatchEvent::Heartbeat
+            );
+            assert_eq!(start.elapsed().as_secs(), beat);
+        }
+
+        file.change();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Write
+        );
+        let write = tokio::time::Instant::now();
+
+        // Real events push back the next heartbeat
+        assert_eq!(
+            timeout(stream.next()).await.unwrap().unwrap(),
+            FileWatchEvent::Heartbeat
+        );
+        assert_eq!(write.elapsed(), interval);
+    }
+
+    #[::std::prelude::v1::test]
+    fn with_new_runtime() {
+        // Created without any runtime running
+        let mut owner = crate::with_new_runtime().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async move {
+            let fut = owner
+                .file(file_path)
+                .unwrap()
+                .modify(true)
+                .next()
+                .await
+                .unwrap();
+
+            file.change();
+
+            assert_eq!(timeout(fut).await.unwrap(), Some(FileWatchEvent::Write));
+
+            owner.shutdown().await;
+        });
+    }
+
+    #[test]
+    async fn install_error() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        // The path is checked when the request is created, but only watched once it is dispatched
+        let request = owner.file(file_path.clone()).unwrap().modify(true);
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert!(matches!(
+            request.watch().await,
+            Err(WatchError::Install(Errno::ENOENT))
+        ));
+
+        let dir_path = test_dir.path().join("subdir");
+        std::fs::create_dir(&dir_path).unwrap();
+
+        let request = owner.dir(&dir_path).unwrap().create(true);
+        std::fs::remove_dir(&dir_path).unwrap();
+
+        assert!(matches!(
+            request.next().await,
+            Err(WatchError::Install(Errno::ENOENT))
+        ));
+
+        // The failure only affects that request
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+        assert_eq!(owner.error(), None);
+    }
+
+    #[test]
+    async fn settle() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let settle = Duration::from_millis(200);
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .modify(true)
+            .settle(settle)
+            .watch()
+            .await
+            .unwrap();
+
+        let temp_path = test_dir.path().join("temp.swp");
+        std::fs::write(&temp_path, "scratch").unwrap();
+        std::fs::remove_file(&temp_path).unwrap();
+
+        let kept_path = test_dir.path().join("kept.txt");
+        let created = tokio::time::Instant::now();
+        std::fs::write(&kept_path, "kept").unwrap();
+
+        let event = timeout(stream.next()).await.unwrap().unwrap();
+        assert!(created.elapsed() >= settle);
+        assert_eq!(event.inner_path.as_deref(), Some("kept.txt"));
+        assert_eq!(event.event, FileWatchEvent::Create);
+
+        // Held events are delivered in order once settled
+        let event = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(event.inner_path.as_deref(), Some("kept.txt"));
+        assert_eq!(event.event, FileWatchEvent::Write);
+
+        assert!(timeout(stream.next()).await.is_err());
+    }
+
+    #[test]
+    async fn is_watching() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        assert!(!owner.is_watching(&file_path).await.unwrap());
+
+        let first = owner
+            .file(&file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let second = owner
+            .file(&file_path)
+            .unwrap()
+            .open(true)
+            .watch()
+            .await
+            .unwrap();
+        assert!(owner.is_watching(&file_path).await.unwrap());
+        assert!(!owner.is_watching(test_dir.path()).await.unwrap());
+
+        // Still watched until every watch on the path is gone
+        drop(first);
+        assert!(owner.is_watching(&file_path).await.unwrap());
+        drop(second);
+        assert!(!owner.is_watching(&file_path).await.unwrap());
+    }
+
+    #[test]
+    async fn end_reason() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(&file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        assert_eq!(stream.end_reason(), None);
+
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(timeout(stream.next()).await.unwrap(), None);
+        assert_eq!(stream.end_reason(), Some(EndReason::FileRemoved));
+        assert!(!owner.is_watching(&file_path).await.unwrap());
+
+        let mut expiring = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .expire_after(Duration::from_millis(100))
+            .watch()
+            .await
+            .unwrap();
+
+        assert_eq!(timeout(expiring.next()).await.unwrap(), None);
+        assert_eq!(expiring.end_reason(), Some(EndReason::Expired));
+    }
+
+    #[test]
+    async fn split_by_kind() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+
+        let mut split = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .delete(true)
+            .modify(true)
+            .watch()
+            .await
+            .unwrap()
+            .split_by_kind();
+
+        let mut file = TestFile::new(file_path.clone());
+        file.change();
+        std::fs::remove_file(&file_path).unwrap();
+
+        let create = timeout(split.create.next()).await.unwrap().unwrap();
+        assert_eq!(create.event, FileWatchEvent::Create);
+        assert_eq!(create.inner_path.as_deref(), Some("test.txt"));
+        assert_eq!(
+            timeout(split.write.next()).await.unwrap().unwrap().event,
+            FileWatchEvent::Write
+        );
+        assert_eq!(
+            timeout(split.delete.next()).await.unwrap().unwrap().event,
+            FileWatchEvent::Delete
+        );
+
+        // The watch is removed once every stream is gone
+        drop(split);
+        wait().await;
+        assert!(!owner.is_watching(test_dir.path()).await.unwrap());
+    }
+
+    #[test]
+    async fn only_names() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .buffer(64)
+            .only_names(["a", "c", "e"])
+            .watch()
+            .await
+            .unwrap();
+
+        for i in 0..16 {
+            TestFile::new(test_dir.path().join(format!("busy{i}")));
+            if i % 5 == 0 {
+                let name = ["a", "b", "c", "d", "e"][i / 5];
+                TestFile::new(test_dir.path().join(name));
+            }
+        }
+
+        for expected in ["a", "c"] {
+            let event = timeout(stream.next()).await.unwrap().unwrap();
+            assert_eq!(event.inner_path.as_deref(), Some(expected));
+        }
+        assert!(timeout(stream.next()).await.is_err());
+    }
+
+    #[test]
+    async fn self_events() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let watched = test_dir.path().join("watched");
+        std::fs::create_dir(&watched).unwrap();
+        TestFile::new(watched.join("test.txt"));
+
+        let mut stream = owner
+            .dir(&watched)
+            .unwrap()
+            .create(true)
+            .self_events(true)
+            .watch()
+            .await
+            .unwrap();
+
+        // Metadata changes to entries are not delivered without asking for them
+        let permissions = std::fs::Permissions::from_mode(0o700);
+        std::fs::set_permissions(watched.join("test.txt"), permissions.clone()).unwrap();
+        std::fs::set_permissions(&watched, permissions).unwrap();
+
+        let event = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(event.event, FileWatchEvent::Metadata);
+        assert_eq!(event.inner_path, None);
+
+        let moved = test_dir.path().join("moved");
+        std::fs::rename(&watched, &moved).unwrap();
+
+        let event = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(event.event, FileWatchEvent::Moved);
+        assert_eq!(event.inner_path, None);
+
+        // The watch follows the directory to its new path
+        TestFile::new(moved.join("new.txt"));
+
+        let event = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(event.event, FileWatchEvent::Create);
+        assert_eq!(event.inner_path.as_deref(), Some("new.txt"));
+    }
+
+    #[test]
+    async fn end_reason_unmounted() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mount = |args: &[&str]| {
+            std::process::Command::new(args[0])
+                .args(&args[1..])
+                .stderr(std::process::Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+
+        let mount_point = test_dir.path().to_str().unwrap();
+        if !mount(&["mount", "-t", "tmpfs", "none", mount_point]) {
+            eprintln!("Skipping, mounting a tmpfs needs root");
+            return;
+        }
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+
+        assert!(mount(&["umount", mount_point]));
+
+        assert_eq!(timeout(stream.next()).await.unwrap(), None);
+        assert_eq!(stream.end_reason(), Some(EndReason::Unmounted));
+    }
+
+    #[test]
+    async fn expected_watches() {
+        let state = |expected_watches| {
+            let (_, request_rx) = tokio::sync::mpsc::channel(1);
+            let (_, drop_rx) = tokio::sync::mpsc::unbounded_channel();
+            let (_, shutdown_rx) = tokio::sync::oneshot::channel();
+
+            WatcherState::new(
+                request_rx,
+                drop_rx,
+                shutdown_rx,
+                Default::default(),
+                None,
+                "test".into(),
+                None,
+                expected_watches,
+            )
+            .unwrap()
+        };
+
+        assert!(state(4096).watch_capacity() >= 4096);
+        assert!(state(0).watch_capacity() < 4096);
+
+        let mut owner = crate::builder()
+            .expected_watches(4096)
+            .build_current()
+            .unwrap();
+        let test_dir = setup_testdir();
+        owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    async fn watch_dedup() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut first = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch_dedup()
+            .await
+            .unwrap();
+        let mut second = owner
+            .dir(test_dir.path().join("."))
+            .unwrap()
+            .create(true)
+            .watch_dedup()
+            .await
+            .unwrap();
+        // A different configuration gets its own watcher
+        let _other = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .delete(true)
+            .watch_dedup()
+            .await
+            .unwrap();
+
+        let dump = owner.dump().await.unwrap();
+        assert_eq!(dump.watches.len(), 1);
+        assert_eq!(dump.watches[0].watchers.len(), 2);
+
+        TestFile::new(test_dir.path().join("a"));
+        for stream in [&mut first, &mut second] {
+            let event = timeout(stream.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(event.inner_path.as_deref(), Some("a"));
+        }
+
+        // The shared watcher is kept until both subscriptions are dropped
+        drop(first);
+        TestFile::new(test_dir.path().join("b"));
+        let event = timeout(second.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(event.inner_path.as_deref(), Some("b"));
+
+        drop(second);
+        wait().await;
+        assert_eq!(owner.dump().await.unwrap().watches[0].watchers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn watch_dedup_concurrent() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut handle = Handle::clone(&owner);
+        let first = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch_dedup();
+        let second = handle
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch_dedup();
+        let (first, second) = tokio::join!(first, second);
+        let (_first, _second) = (first.unwrap(), second.unwrap());
+
+        let dump = owner.dump().await.unwrap();
+        assert_eq!(dump.watches.len(), 1);
+        assert_eq!(dump.watches[0].watchers.len(), 1);
+    }
+
+    #[::std::prelude::v1::test]
+    fn resource_limits() {
+        let limits = [Errno::EMFILE, Errno::ENFILE, Errno::ENOMEM, Errno::ENOSPC].map(|errno| {
+            match WatchError::from(errno) {
+                WatchError::Limit(limit) => limit,
+                other => panic!("{errno} was not a resource limit, got {other:?}"),
+            }
+        });
+
+        assert_eq!(
+            limits,
+            [
+                ResourceLimit::ProcessFiles,
+                ResourceLimit::SystemFiles,
+                ResourceLimit::Memory,
+                ResourceLimit::Watches,
+            ]
+        );
+        assert!(WatchError::Limit(ResourceLimit::Watches)
+            .to_string()
+            .contains("max_user_watches"));
+        let messages = limits.map(|limit| limit.to_string());
+        for (i, message) in messages.iter().enumerate() {
+            assert!(!messages[i + 1..].contains(message), "{message}");
+        }
+
+        assert!(matches!(
+            WatchError::from(Errno::ENOENT),
+            WatchError::Install(Errno::ENOENT)
+        ));
+    }
+
+    #[test]
+    async fn shutdown_drains_requests() {
+        let owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut watch_handle = Handle::clone(&owner);
+        let stats_handle = Handle::clone(&owner);
+        let mut watch = Box::pin(
+            watch_handle
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch(),
+        );
+        let mut stats = Box::pin(stats_handle.stats());
+
+        // Queue both requests without letting the task run, so that it sees the shutdown at the
+        // same time as them
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        assert!(watch.as_mut().poll(&mut cx).is_pending());
+        assert!(stats.as_mut().poll(&mut cx).is_pending());
+        owner.shutdown().await;
+
+        assert!(watch.await.is_ok());
+        assert_eq!(stats.await.unwrap().watches, 1);
+    }
+
+    #[test]
+    async fn watch_until() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let cancel = std::sync::Arc::new(tokio::sync::Notify::new());
+
+        let mut stream = {
+            let cancel = cancel.clone();
+            owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch_until(async move { cancel.notified().await })
+                .await
+                .unwrap()
+        };
+
+        TestFile::new(test_dir.path().join("a"));
+        let event = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(event.inner_path.as_deref(), Some("a"));
+
+        // Removed without the stream being polled
+        cancel.notify_one();
+        wait().await;
+        assert!(owner.dump().await.unwrap().watches.is_empty());
+        assert_eq!(timeout(stream.next()).await.unwrap(), None);
+    }
+
+    #[test]
+    async fn path_style() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+
+        let mut streams = Vec::new();
+        for style in [
+            PathStyle::Bare,
+            PathStyle::JoinedWithWatch,
+            PathStyle::RelativeToWatch,
+        ] {
+            let stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .path_style(style)
+                .watch()
+                .await
+                .unwrap();
+            streams.push(stream);
+        }
+
+        TestFile::new(file_path.clone());
+
+        let bare = timeout(streams[0].next()).await.unwrap().unwrap();
+        assert_eq!(bare.inner_path.as_deref(), Some("test.txt"));
+        let joined = timeout(streams[1].next()).await.unwrap().unwrap();
+        assert_eq!(joined.inner_path.as_deref(), file_path.to_str());
+        let relative = timeout(streams[2].next()).await.unwrap().unwrap();
+        assert_eq!(relative.inner_path.as_deref(), Some("test.txt"));
+        assert_eq!(PathStyle::default(), PathStyle::Bare);
+    }
+
+    #[test]
+    async fn abort_handle() {
+        use nix::sys::inotify::{InitFlags, Inotify};
+        use std::os::fd::AsRawFd;
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK).unwrap();
+        let mut owner = crate::builder()
+            .with_inotify(inotify)
+            .build_current()
+            .unwrap();
+        let test_dir = setup_testdir();
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+
+        let abort = owner.abort_handle();
+        abort.abort();
+
+        assert_eq!(timeout(stream.next()).await.unwrap(), None);
+        assert_eq!(stream.end_reason(), Some(EndReason::InstanceClosed));
+        assert!(owner.wait().await.unwrap_err().is_cancelled());
+        // Dropping the aborted task closed the instance, so the watch is gone too
+        assert!(!is_watched_by(inotify.as_raw_fd(), test_dir.path()));
+    }
+
+    #[test]
+    async fn build_ready() {
+        for dedicated_thread in [false, true] {
+            let (mut owner, ready) = crate::builder()
+                .dedicated_thread(dedicated_thread)
+                .runtime(tokio::runtime::Handle::current())
+                .build_ready()
+                .unwrap();
+            timeout(ready).await.unwrap();
+
+            let test_dir = setup_testdir();
+            let mut stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch()
+                .await
+                .unwrap();
+
+            TestFile::new(test_dir.path().join("test.txt"));
+            let event = timeout(stream.next()).await.unwrap().unwrap();
+            assert_eq!(event.event, FileWatchEvent::Create);
+        }
+    }
+
+    #[test]
+    async fn exclusive() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let link_path = test_dir.path().join("link.txt");
+        let _file = TestFile::new(file_path.clone());
+        std::fs::hard_link(&file_path, &link_path).unwrap();
+
+        let _first = owner
+            .file(file_path.clone())
+            .unwrap()
+            .modify(true)
+            .exclusive(true)
+            .watch()
+            .await
+            .unwrap();
+
+        // Same path is still shared
+        let _same = owner
+            .file(file_path)
+            .unwrap()
+            .close(true)
+            .exclusive(true)
+            .watch()
+            .await
+            .unwrap();
+
+        let linked = owner
+            .file(link_path)
+            .unwrap()
+            .open(true)
+            .exclusive(true)
+            .watch()
+            .await;
+        assert!(matches!(linked, Err(WatchError::AlreadyWatched)));
+
+        let snapshot = owner.dump().await.unwrap();
+        assert_eq!(snapshot.watches.len(), 1);
+        assert_eq!(snapshot.watches[0].watchers.len(), 2);
+        assert_eq!(
+            snapshot.watches[0].mask,
+            (AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_CLOSE).bits()
+        );
+    }
+
+    #[test]
+    async fn independent_watchers() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut write = owner
+            .file(file_path.clone())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut metadata = owner
+            .file(file_path.clone())
+            .unwrap()
+            .metadata(true)
+            .watch()
+            .await
+            .unwrap();
+        let once = owner
+            .file(file_path.clone())
+            .unwrap()
+            .modify(true)
+            .next()
+            .await
+            .unwrap();
+
+        let snapshot = owner.dump().await.unwrap();
+        assert_eq!(snapshot.watches.len(), 1);
+        assert_eq!(snapshot.watches[0].watchers.len(), 3);
+        assert_eq!(
+            snapshot.watches[0].mask,
+            (AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_ATTRIB).bits()
+        );
+
+        file.change();
+
+        assert_eq!(
+            timeout(write.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(timeout(once).await.unwrap(), Some(FileWatchEvent::Write));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), metadata.next())
+                .await
+                .is_err()
+        );
+
+        let permissions = std::fs::metadata(&file_path).unwrap().permissions();
+        std::fs::set_permissions(&file_path, permissions.clone()).unwrap();
+
+        assert_eq!(
+            timeout(metadata.next()).await.unwrap(),
+            Some(FileWatchEvent::Metadata)
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), write.next())
+                .await
+                .is_err()
+        );
+
+        // Dropping one watcher leaves the other, and the mask, to just its own events
+        drop(write);
+        wait().await;
+
+        let snapshot = owner.dump().await.unwrap();
+        assert_eq!(snapshot.watches[0].watchers.len(), 1);
+        assert_eq!(snapshot.watches[0].mask, AddWatchFlags::IN_ATTRIB.bits());
+
+        file.change();
+        std::fs::set_permissions(&file_path, permissions).unwrap();
+        assert_eq!(
+            timeout(metadata.next()).await.unwrap(),
+            Some(FileWatchEvent::Metadata)
+        );
+    }
+
+    #[test]
+    async fn close_kinds() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut any = owner
+            .file(file_path.clone())
+            .unwrap()
+            .close(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut write = owner
+            .file(file_path.clone())
+            .unwrap()
+            .close_write(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut nowrite = owner
+            .file(file_path.clone())
+            .unwrap()
+            .close_nowrite(true)
+            .watch()
+            .await
+            .unwrap();
+
+        file.change();
+        std::fs::read(&file_path).unwrap();
+
+        assert_eq!(
+            timeout(any.next()).await.unwrap(),
+            Some(FileWatchEvent::Close { writable: true })
+        );
+        assert_eq!(
+            timeout(any.next()).await.unwrap(),
+            Some(FileWatchEvent::Close { writable: false })
+        );
+        assert_eq!(
+            timeout(write.next()).await.unwrap(),
+            Some(FileWatchEvent::Close { writable: true })
+        );
+        assert_eq!(
+            timeout(nowrite.next()).await.unwrap(),
+            Some(FileWatchEvent::Close { writable: false })
+        );
+
+        wait().await;
+        let snapshot = owner.dump().await.unwrap();
+        assert_eq!(snapshot.watches[0].mask, AddWatchFlags::IN_CLOSE.bits());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), write.next())
+                .await
+                .is_err()
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), nowrite.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    async fn moves() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let watched = test_dir.path().join("watched");
+        let other = test_dir.path().join("other");
+        std::fs::create_dir(&watched).unwrap();
+        std::fs::create_dir(&other).unwrap();
+        TestFile::new(watched.join("a.txt"));
+        TestFile::new(other.join("c.txt"));
+
+        let mut stream = owner
+            .dir(&watched)
+            .unwrap()
+            .moves(true)
+            .watch()
+            .await
+            .unwrap();
+
+        std::fs::rename(watched.join("a.txt"), watched.join("b.txt")).unwrap();
+        std::fs::rename(watched.join("b.txt"), other.join("b.txt")).unwrap();
+        std::fs::rename(other.join("c.txt"), watched.join("c.txt")).unwrap();
+
+        let renamed = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(renamed.event, FileWatchEvent::Moved);
+        assert_eq!(renamed.inner_path.as_deref(), Some("a.txt"));
+        assert_eq!(renamed.moved_to.as_deref(), Some("b.txt"));
+        assert_eq!(renamed.to_string(), "a.txt was moved to b.txt");
+
+        let moved_out = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(moved_out.event, FileWatchEvent::Moved);
+        assert_eq!(moved_out.inner_path.as_deref(), Some("b.txt"));
+        assert_eq!(moved_out.moved_to, None);
+
+        let moved_in = timeout(stream.next()).await.unwrap().unwrap();
+        assert_eq!(moved_in.event, FileWatchEvent::Create);
+        assert_eq!(moved_in.inner_path.as_deref(), Some("c.txt"));
+        assert_eq!(moved_in.moved_to, None);
+    }
+
+    #[test]
+    async fn create_retry() {
+        let test_dir = setup_testdir();
+
+        let mut owner = crate::builder()
+            .create_retry(2, Duration::from_millis(10))
+            .build_current()
+            .unwrap();
+
+        owner
+            .request_tx
+            .try_send(WatchRequestInner::FailCreates(vec![
+                Errno::ENOSPC,
+                Errno::EMFILE,
+            ]))
+            .unwrap();
+        let watch = owner.dir(test_dir.path()).unwrap().create(true).watch();
+        let _watch = timeout(watch).await.unwrap().unwrap();
+
+        // Not retried once the attempts run out, or for errors which are not limits
+        for (name, errors) in [
+            ("a.txt", vec![Errno::ENOSPC, Errno::ENOSPC, Errno::ENOSPC]),
+            ("b.txt", vec![Errno::EACCES]),
+        ] {
+            let path = test_dir.path().join(name);
+            TestFile::new(path.clone());
+            let expected = WatchError::from(*errors.last().unwrap());
+            owner
+                .request_tx
+                .try_send(WatchRequestInner::FailCreates(errors))
+                .unwrap();
+            let watch = owner.file(path).unwrap().modify(true).watch();
+            let result = timeout(watch).await.unwrap();
+            assert_eq!(result.err().unwrap().to_string(), expected.to_string());
+        }
+        assert_eq!(owner.stats().await.unwrap().watches, 1);
+
+        // Fails straight away without retries
+        let mut owner = crate::new().unwrap();
+        owner
+            .request_tx
+            .try_send(WatchRequestInner::FailCreates(vec![Errno::ENOSPC]))
+            .unwrap();
+        let result = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await;
+        assert!(matches!(
+            result,
+            Err(WatchError::Limit(ResourceLimit::Watches))
+        ));
+    }
+
+    #[test]
+    async fn timestamps() {
+        let test_dir = setup_testdir();
+
+        for mode in [
+            TimestampMode::None,
+            TimestampMode::Wall,
+            TimestampMode::Both,
+        ] {
+            let mut owner = crate::builder().timestamps(mode).build_current().unwrap();
+            let mut stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch()
+                .await
+                .unwrap();
+
+            let (before, before_wall) = (std::time::Instant::now(), SystemTime::now());
+            TestFile::new(test_dir.path().join(format!("{mode:?}")));
+            let event = timeout(stream.next()).await.unwrap().unwrap();
+            let (after, after_wall) = (std::time::Instant::now(), SystemTime::now());
+
+            match mode {
+                TimestampMode::None => assert_eq!(event.timestamp, Timestamp::None),
+                TimestampMode::Wall => {
+                    assert_eq!(event.timestamp.monotonic(), None);
+                    let at = event.timestamp.wall().unwrap();
+                    assert!(before_wall <= at && at <= after_wall);
+                }
+                _ => {
+                    let at = event.timestamp.monotonic().unwrap();
+                    assert!(before <= at && at <= after);
+                    let at = event.timestamp.wall().unwrap();
+                    assert!(before_wall <= at && at <= after_wall);
+                }
+            }
+        }
+    }
+
+    #[::std::prelude::v1::test]
+    fn event_flags() {
+        let events = [
+            FileWatchEvent::Read,
+            FileWatchEvent::Write,
+            FileWatchEvent::Open,
+            FileWatchEvent::Close { writable: false },
+            FileWatchEvent::Close { writable: true },
+            FileWatchEvent::Create,
+            FileWatchEvent::Delete,
+            FileWatchEvent::Metadata,
+            FileWatchEvent::Moved,
+        ];
+
+        for event in events {
+            let flags = AddWatchFlags::from(&event);
+            assert!(!flags.is_empty(), "{event:?}");
+            assert!(event.matches(flags), "{event:?}");
+
+            // One of them is the flag inotify reports the event with
+            let reported = (0..32)
+                .map(|bit| AddWatchFlags::from_bits_truncate(1 << bit) & flags)
+                .any(|flag| FileWatchEvent::try_from(flag) == Ok(event));
+            assert!(reported, "{event:?}");
+        }
+
+        assert!(FileWatchEvent::Close { writable: true }.matches(AddWatchFlags::IN_CLOSE));
+        assert!(!FileWatchEvent::Close { writable: true }.matches(AddWatchFlags::IN_CLOSE_NOWRITE));
+        assert!(!FileWatchEvent::Heartbeat.matches(AddWatchFlags::all()));
+    }
+
+    #[test(start_paused = true)]
+    async fn paused_clock() {
+        let mut owner = crate::builder()
+            .timestamps(TimestampMode::Monotonic)
+            .build_current()
+            .unwrap();
+        let test_dir = setup_testdir();
+        let settle = Duration::from_secs(10 * 60);
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .settle(settle)
+            .watch()
+            .await
+            .unwrap();
+
+        let start = tokio::time::Instant::now();
+        let real_start = std::time::Instant::now();
+        TestFile::new(test_dir.path().join("test.txt"));
+
+        // Any shorter timeout would be reached before the entry settles, as the paused clock
+        // jumps straight to the next timer whenever the runtime is idle
+        // The paused clock jumps straight to the next timer whenever the runtime is idle, even
+        // if the event is already waiting to be read, so only start one once it has been
+        while owner.stats().await.unwrap().events == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let event = tokio::time::timeout(settle * 2, stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.event, FileWatchEvent::Create);
+
+        assert!(start.elapsed() >= settle);
+        assert!(real_start.elapsed() < Duration::from_secs(10));
+
+        // Stamped when it was read, before it was held back to settle
+        let read = event.timestamp.monotonic().unwrap();
+        assert!(read.duration_since(start.into_std()) < settle);
+    }
+
+    #[test]
+    async fn once_mode() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        // Captures opens for the path, so they are reported along with writes
+        let _open = owner
+            .file(&file_path)
+            .unwrap()
+            .open(true)
+            .watch()
+            .await
+            .unwrap();
+
+        let matching = owner
+            .file(&file_path)
+            .unwrap()
+            .modify(true)
+            .next()
+            .await
+            .unwrap();
+        let any = owner
+            .file(&file_path)
+            .unwrap()
+            .modify(true)
+            .once(Once::Any)
+            .next()
+            .await
+            .unwrap();
+
+        // Opening the file comes before writing to it
+        file.change();
+
+        assert_eq!(
+            timeout(matching).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(timeout(any).await.unwrap(), Some(FileWatchEvent::Open));
+    }
+
+    #[test]
+    async fn write_complete() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(&file_path)
+            .unwrap()
+            .write_complete(true)
+            .watch()
+            .await
+            .unwrap();
+
+        let mut file = std::fs::File::create(&file_path).unwrap();
+        for part in ["first", "second", "third"] {
+            file.write_all(part.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+        wait().await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), stream.next())
+                .await
+                .is_err()
+        );
+
+        drop(file);
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+
+        // Nothing was written this time
+        drop(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&file_path)
+                .unwrap(),
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), stream.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    async fn eventual_file() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+
+        assert!(matches!(
+            owner.eventual_file(test_dir.path().join("missing/test.txt")),
+            Err(RequestError::DoesNotExist(_))
+        ));
+
+        let mut stream = owner
+            .eventual_file(&file_path)
+            .unwrap()
+            .modify(true)
+            .watch_atomic()
+            .await
+            .unwrap();
+
+        let mut file = TestFile::new(file_path.clone());
+        file.change();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Create)
+        );
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+
+        // Picked up again after being deleted and recreated
+        std::fs::remove_file(&file_path).unwrap();
+        TestFile::new(file_path.clone());
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Delete)
+        );
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Create)
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), stream.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    async fn mute() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(&file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        stream.mute().await.unwrap();
+        file.change();
+        wait().await;
+        stream.unmute().await.unwrap();
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), stream.next())
+                .await
+                .is_err()
+        );
+        assert!(owner.is_watching(&file_path).await.unwrap());
+
+        file.change();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+    }
+
+    #[test]
+    async fn pre_watch() {
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let (owner, watched) = crate::builder()
+            .pre_watch(&file_path, &WatchConfig::<FileEvents>::new().modify(true))
+            .pre_watch(
+                test_dir.path(),
+                &WatchConfig::<DirectoryEvents>::new().create(true),
+            )
+            .runtime(tokio::runtime::Handle::current())
+            .build_watching()
+            .unwrap();
+        assert_eq!(owner.pending_requests(), 0);
+
+        // Installed already, so changes made straight away are seen
+        file.change();
+        TestFile::new(test_dir.path().join("other.txt"));
+
+        let mut watched = watched.into_iter();
+        let mut file_stream = watched.next().unwrap().into_file().unwrap();
+        let mut dir_stream = watched.next().unwrap().into_dir().unwrap();
+
+        assert_eq!(
+            timeout(file_stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        let event = timeout(dir_stream.next()).await.unwrap().unwrap();
+        assert_eq!(event.event, FileWatchEvent::Create);
+        assert_eq!(event.inner_path.as_deref(), Some("other.txt"));
+
+        let missing = crate::builder()
+            .pre_watch(
+                test_dir.path().join("missing"),
+                &WatchConfig::<DirectoryEvents>::new().create(true),
+            )
+            .runtime(tokio::runtime::Handle::current())
+            .build_watching();
+        assert!(matches!(
+            missing,
+            Err(AnotifyError::Request(RequestError::DoesNotExist(_)))
+        ));
+    }
+
+    #[test]
+    async fn watch_id() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let other_dir = test_dir.path().join("other");
+        std::fs::create_dir(&other_dir).unwrap();
+
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch()
+                .await
+                .unwrap();
+            streams.push(stream);
+        }
+
+        let ids = streams
+            .iter()
+            .enumerate()
+            .map(|(i, stream)| (stream.id(), i))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids.values().copied().collect::<Vec<_>>(), [0, 1, 2]);
+
+        let id = streams[0].id();
+        assert_eq!(id.to_string(), format!("watch#{}", id.0));
+
+        let snapshot = owner.dump().await.unwrap();
+        assert!(snapshot.watches[0]
+            .watchers
+            .iter()
+            .any(|watcher| watcher.id == id));
+
+        streams[0].rewatch(&other_dir).await.unwrap();
+        assert_eq!(streams[0].id(), id);
+    }
+
+    #[test]
+    async fn watch_state() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let _a = TestFile::new(test_dir.path().join("a"));
+        std::fs::create_dir(test_dir.path().join("sub")).unwrap();
+
+        let mut state = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .watch_state()
+            .await
+            .unwrap();
+
+        let names = |entries: Vec<DirEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| (entry.name.to_string(), entry.is_dir))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(timeout(state.next()).await.unwrap().unwrap()),
+            [("a".into(), false), ("sub".into(), true)]
+        );
+
+        let _b = TestFile::new(test_dir.path().join("b"));
+        assert_eq!(
+            names(timeout(state.next()).await.unwrap().unwrap()),
+            [
+                ("a".into(), false),
+                ("b".into(), false),
+                ("sub".into(), true)
+            ]
+        );
+
+        std::fs::rename(test_dir.path().join("a"), test_dir.path().join("c")).unwrap();
+        assert_eq!(
+            names(timeout(state.next()).await.unwrap().unwrap()),
+            [
+                ("b".into(), false),
+                ("c".into(), false),
+                ("sub".into(), true)
+            ]
+        );
+
+        std::fs::remove_dir(test_dir.path().join("sub")).unwrap();
+        assert_eq!(
+            names(timeout(state.next()).await.unwrap().unwrap()),
+            [("b".into(), false), ("c".into(), false)]
+        );
+
+        // Missed while muted, so only found by listing again after events were lost
+        state.inner.mute().await.unwrap();
+        let _d = TestFile::new(test_dir.path().join("d"));
+        wait().await;
+        state.inner.unmute().await.unwrap();
+        owner
+            .request_tx
+            .try_send(WatchRequestInner::Recoverable(EventError::QueueOverflow))
+            .unwrap();
+        assert_eq!(
+            names(timeout(state.next()).await.unwrap().unwrap()),
+            [
+                ("b".into(), false),
+                ("c".into(), false),
+                ("d".into(), false)
+            ]
+        );
+    }
+
+    #[test]
+    async fn debug_streams() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        #[derive(Debug)]
+        struct Watching {
+            _stream: DirectoryWatchStream,
+        }
+
+        let stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+        let id = stream.id();
+
+        let watching = Watching { _stream: stream };
+        let debug = format!("{watching:?}");
+        assert!(debug.contains(&format!("id: {id:?}")), "{debug}");
+        assert!(
+            debug.contains(&format!("path: {:?}", test_dir.path())),
+            "{debug}"
+        );
+        assert!(debug.contains("end_reason: None"), "{debug}");
+    }
+
+    #[test]
+    async fn log_to() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .delete(true)
+            .watch()
+            .await
+            .unwrap();
+        let log = stream.log_to(Vec::new(), LogFormat::Jsonl);
+
+        std::fs::write(test_dir.path().join("a \"b\""), "").unwrap();
+        std::fs::remove_file(test_dir.path().join("a \"b\"")).unwrap();
+        wait().await;
+
+        // Ends the stream, so the task flushes and returns the sink
+        owner.unwatch_prefix(test_dir.path()).await.unwrap();
+        let log = timeout(log).await.unwrap().unwrap().unwrap();
+
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            concat!(
+                r#"{"path":"a \"b\"","event":"create","is_dir":false,"watch":"directory"}"#,
+                "\n",
+                r#"{"path":"a \"b\"","event":"delete","is_dir":false,"watch":"directory"}"#,
+                "\n",
+            )
+        );
+
+        let stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+        let log = stream.log_to(Vec::new(), LogFormat::Text);
+
+        let _file = TestFile::new(test_dir.path().join("c"));
+        wait().await;
+
+        owner.unwatch_prefix(test_dir.path()).await.unwrap();
+        let log = timeout(log).await.unwrap().unwrap().unwrap();
+        assert_eq!(String::from_utf8(log).unwrap(), "c was created\n");
+    }
+
+    #[::std::prelude::v1::test]
+    fn from_mask() {
+        assert_eq!(
+            FileWatchEvent::from_mask(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ISDIR),
+            Some(FileWatchEvent::Create)
+        );
+        assert_eq!(
+            FileWatchEvent::from_mask(AddWatchFlags::IN_CLOSE_WRITE),
+            Some(FileWatchEvent::Close { writable: true })
+        );
+        assert_eq!(
+            FileWatchEvent::from_mask(AddWatchFlags::IN_MOVED_TO),
+            Some(FileWatchEvent::Create)
+        );
+        assert_eq!(
+            FileWatchEvent::from_mask(AddWatchFlags::IN_MOVED_FROM | AddWatchFlags::IN_ISDIR),
+            Some(FileWatchEvent::Delete)
+        );
+        assert_eq!(FileWatchEvent::from_mask(AddWatchFlags::IN_IGNORED), None);
+        assert_eq!(FileWatchEvent::from_mask(AddWatchFlags::IN_ISDIR), None);
+    }
+
+    #[::std::prelude::v1::test]
+    fn no_runtime() {
+        assert!(matches!(crate::new(), Err(InitError::NoRuntime)));
+        assert!(matches!(
+            crate::builder().build_current(),
+            Err(InitError::NoRuntime)
+        ));
+    }
+
+    #[::std::prelude::v1::test]
+    fn given_runtime() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // Built outside of any runtime, with the task spawned onto the one given
+        let mut owner = crate::builder()
+            .runtime(runtime.handle().clone())
+            .build()
+            .unwrap();
+        let test_dir = setup_testdir();
+
+        runtime.block_on(async {
+            let mut stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch()
+                .await
+                .unwrap();
+
+            TestFile::new(test_dir.path().join("a"));
+            let event = timeout(stream.next()).await.unwrap().unwrap();
+            assert_eq!(event.inner_path.as_deref(), Some("a"));
+        });
+    }
+
+    #[test]
+    async fn auto_close_on_self_removal() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let path = test_dir.path().join("test.txt");
+        let _file = TestFile::new(path.clone());
+
+        let mut stream = owner
+            .file(&path)
+            .unwrap()
+            .modify(true)
+            .auto_close_on_self_removal(false)
+            .watch()
+            .await
+            .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Delete)
+        );
+
+        // Still registered, so sees the file once it is created again
+        std::fs::write(&path, "again").unwrap();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Create)
+        );
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(stream.end_reason(), None);
+    }
+
+    #[test]
+    async fn with_inotify() {
+        use nix::{
+            fcntl::{fcntl, FcntlArg, OFlag},
+            sys::inotify::{InitFlags, Inotify},
+        };
+        use std::os::fd::AsRawFd;
+
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC).unwrap();
+        let mut owner = crate::builder()
+            .with_inotify(inotify)
+            .build_current()
+            .unwrap();
+
+        let flags = fcntl(inotify.as_raw_fd(), FcntlArg::F_GETFL).unwrap();
+        assert!(OFlag::from_bits_truncate(flags).contains(OFlag::O_NONBLOCK));
+        assert_eq!(
+            owner.init_flags().await.unwrap(),
+            InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC
+        );
+
+        let test_dir = setup_testdir();
+        let mut file = TestFile::new(test_dir.path().join("test.txt"));
+        let fut = owner
+            .file(&file.0)
+            .unwrap()
+            .modify(true)
+            .next()
+            .await
+            .unwrap();
+
+        file.change();
+        assert_eq!(timeout(fut).await.unwrap(), Some(FileWatchEvent::Write));
+
+        // A clone creates its own instance rather than sharing the fd
+        let given = Inotify::init(InitFlags::IN_NONBLOCK).unwrap();
+        let builder = crate::builder().with_inotify(given);
+        let mut clone = builder.clone().build_current().unwrap();
+        let _stream = clone
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+        assert!(!is_watched_by(given.as_raw_fd(), test_dir.path()));
+        let _original = builder.build_current().unwrap();
+    }
+
+    #[test]
+    async fn buffered_len() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        for overflow in [Overflow::DropNewest, Overflow::DropOldest] {
+            let mut stream = owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .buffer(8)
+                .overflow(overflow)
+                .watch()
+                .await
+                .unwrap();
+            assert_eq!(stream.capacity(), 8);
+            assert!(stream.is_empty());
+
+            let files = (0..3)
+                .map(|i| TestFile::new(test_dir.path().join(format!("{overflow:?}-{i}"))))
+                .collect::<Vec<_>>();
+            wait().await;
+            assert_eq!(stream.len(), 3);
+
+            timeout(stream.next()).await.unwrap().unwrap();
+            assert_eq!(stream.len(), 2);
+            drop(files);
+        }
+    }
+
+    #[test(flavor = "multi_thread")]
+    async fn build_manual() {
+        let (mut handle, watcher) = crate::builder().build_manual().unwrap();
+        let test_dir = setup_testdir();
+        let mut file = TestFile::new(test_dir.path().join("test.txt"));
+
+        // Drive the watcher alongside the watch, rather than on a task of its own
+        let watch = async {
+            let fut = handle
+                .file(&file.0)
+                .unwrap()
+                .modify(true)
+                .next()
+                .await
+                .unwrap();
+
+            file.change();
+            let event = timeout(fut).await.unwrap();
+            drop(handle);
+            event
+        };
+
+        let (event, ()) = timeout(async { tokio::join!(watch, watcher) })
+            .await
+            .unwrap();
+        assert_eq!(event, Some(FileWatchEvent::Write));
+    }
+
+    #[test(flavor = "multi_thread")]
+    async fn watch_removed_while_installing() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let path = test_dir.path().join("test.txt");
+
+        for _ in 0..200 {
+            std::fs::write(&path, "").unwrap();
+            let remove = std::thread::spawn({
+                let path = path.clone();
+                move || std::fs::remove_file(path).unwrap()
+            });
+
+            // Removed before, during, or after installing, depending on the thread
+            let stream = match owner.file(&path) {
+                Ok(request) => request.modify(true).watch().await,
+                Err(RequestError::DoesNotExist(_)) => {
+                    remove.join().unwrap();
+                    continue;
+                }
+                Err(e) => panic!("unexpected error {e}"),
+            };
+            remove.join().unwrap();
+
+            match stream {
+                Ok(mut stream) => {
+                    assert_eq!(timeout(stream.next()).await.unwrap(), None);
+                    assert_eq!(stream.end_reason(), Some(EndReason::FileRemoved));
+                }
+                Err(WatchError::Install(Errno::ENOENT)) => {}
+                Err(e) => panic!("unexpected error {e}"),
+            }
+        }
+
+        assert_eq!(owner.error(), None);
+
+        // A path replaced by a new file is watched afresh, even if it was watched before
+        let _first = TestFile::new(path.clone());
+        let _old = owner
+            .file(&path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut file = TestFile::new(path.clone());
+
+        let mut new = owner
+            .file(&path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        file.change();
+        assert_eq!(
+            timeout(new.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert_eq!(owner.stats().await.unwrap().watches, 1);
+    }
+
+    #[test]
+    async fn watch_take() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        assert!(matches!(
+            owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .watch_take(0)
+                .await,
+            Err(WatchError::NoEvents)
+        ));
+
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch_take(2)
+            .await
+            .unwrap();
+
+        let _files = (0..3)
+            .map(|i| TestFile::new(test_dir.path().join(format!("{i}.txt"))))
+            .collect::<Vec<_>>();
+
+        let mut names = Vec::new();
+        while let Some(event) = timeout(stream.next()).await.unwrap() {
+            names.push(event.inner_path.unwrap().to_string());
+        }
+
+        assert_eq!(names, ["0.txt", "1.txt"]);
+        assert_eq!(stream.end_reason(), Some(EndReason::Taken));
+        // Removed by the task, while the stream is still alive
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+    }
+
+    #[test]
+    async fn raise_limits() {
+        use nix::sys::resource::{getrlimit, Resource};
+
+        let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        let _owner = crate::builder().raise_limits(true).build_current().unwrap();
+
+        // Either raised, or left alone if that was not allowed
+        let (raised, _) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        assert!(
+            raised == soft || raised == hard,
+            "{soft} -> {raised} of {hard}"
+        );
+    }
+
+    #[test]
+    async fn watch_batches() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let batches = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let watch = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch_batches({
+                let batches = batches.clone();
+                move |events: &[crate::futures::DirectoryWatchEvent]| {
+                    batches.lock().unwrap().push(
+                        events
+                            .iter()
+                            .map(|it| it.inner_path.as_deref().unwrap().to_string())
+                            .collect::<Vec<_>>(),
+                    )
+                }
+            })
+            .await
+            .unwrap();
+
+        let _files = (0..5)
+            .map(|i| TestFile::new(test_dir.path().join(format!("{i}.txt"))))
+            .collect::<Vec<_>>();
+        wait().await;
+
+        let batches = std::mem::take(&mut *batches.lock().unwrap());
+        // How the events are split depends on when the task reads, but never into empty calls
+        assert!(batches.iter().all(|batch| !batch.is_empty()));
+        assert_eq!(
+            batches.concat(),
+            ["0.txt", "1.txt", "2.txt", "3.txt", "4.txt"]
+        );
+        assert_eq!(watch.end_reason(), None);
+
+        drop(watch);
+        wait().await;
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+    }
+
+    #[test]
+    async fn init_flags() {
+        use nix::sys::inotify::InitFlags;
+
+        let owner = crate::new().unwrap();
+        assert_eq!(owner.init_flags().await.unwrap(), InitFlags::IN_NONBLOCK);
+
+        let owner = crate::builder()
+            .init_flags(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .build_current()
+            .unwrap();
+        assert_eq!(
+            owner.init_flags().await.unwrap(),
+            InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC
+        );
+
+        assert!(matches!(
+            crate::builder().init_flags(InitFlags::IN_CLOEXEC).build_current(),
+            Err(InitError::Blocking(flags)) if flags == InitFlags::IN_CLOEXEC
+        ));
+    }
+
+    #[::std::prelude::v1::test]
+    fn event_eq_ignores_timestamp() {
+        let event = crate::futures::DirectoryWatchEvent {
+            inner_path: Some("a.txt".into()),
+            event: FileWatchEvent::Create,
+            is_dir: false,
+            raw_mask: None,
+            watch_kind: crate::futures::WatchKind::Directory,
+            moved_to: None,
+            seq: None,
+            timestamp: Timestamp::None,
+        };
+        let later = crate::futures::DirectoryWatchEvent {
+            timestamp: Timestamp::Wall(SystemTime::now()),
+            ..event.clone()
+        };
+
+        assert_eq!(event, later);
+        assert_eq!(
+            [event.clone(), later.clone()]
+                .into_iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            1
+        );
+        assert!(!event.same_occurrence(&later));
+        assert!(later.same_occurrence(&later.clone()));
+
+        let other = crate::futures::DirectoryWatchEvent {
+            inner_path: Some("b.txt".into()),
+            ..event.clone()
+        };
+        assert_ne!(event, other);
+    }
+
+    #[test]
+    async fn replay_since() {
+        use crate::futures::Replay;
+
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let keeper = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .history(2)
+            .shared()
+            .await
+            .unwrap();
+        let id = keeper.id();
+
+        let mut consumer = keeper.clone();
+        let _first = TestFile::new(test_dir.path().join("0.txt"));
+        let event = timeout(consumer.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(event.seq, Some(1));
+        let cursor = event.seq.unwrap();
+
+        // Missed while there is no consumer
+        drop(consumer);
+        let _rest = (1..3)
+            .map(|i| TestFile::new(test_dir.path().join(format!("{i}.txt"))))
+            .collect::<Vec<_>>();
+        wait().await;
+
+        let Some(Replay::Events(events)) = owner.replay_since(id, cursor).await.unwrap() else {
+            panic!("Expected the missed events to be kept");
+        };
+        assert_eq!(
+            events
+                .iter()
+                .map(|it| (it.seq.unwrap(), it.inner_path.as_deref().unwrap()))
+                .collect::<Vec<_>>(),
+            [(2, "1.txt"), (3, "2.txt")]
+        );
+
+        assert_eq!(
+            owner.replay_since(id, 3).await.unwrap(),
+            Some(Replay::Events(Vec::new()))
+        );
+        // The first event has been pushed out of the history
+        assert_eq!(owner.replay_since(id, 0).await.unwrap(), Some(Replay::Gap));
+
+        drop(keeper);
+        wait().await;
+        assert_eq!(owner.replay_since(id, 0).await.unwrap(), None);
+    }
+
+    #[test]
+    async fn supported_flags() {
+        use crate::futures::WatchKind;
+
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let file = owner.supported_flags(WatchKind::File);
+        let dir = owner.supported_flags(WatchKind::Directory);
+        assert!(file.contains(AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_DONT_FOLLOW));
+        assert!(!file.contains(AddWatchFlags::IN_ONESHOT));
+        assert!(!file.contains(AddWatchFlags::IN_ONLYDIR));
+        assert_eq!(dir, file | AddWatchFlags::IN_ONLYDIR);
+        assert!(!dir.intersects(AddWatchFlags::IN_ISDIR | AddWatchFlags::IN_Q_OVERFLOW));
+
+        let _dir_watch = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .raw_flags(dir)
+            .watch()
+            .await
+            .unwrap();
+        assert!(matches!(
+            owner
+                .dir(test_dir.path())
+                .unwrap()
+                .raw_flags(AddWatchFlags::IN_ISDIR)
+                .watch()
+                .await,
+            Err(WatchError::InvalidFlags(AddWatchFlags::IN_ISDIR))
+        ));
+    }
+
+    #[test]
+    async fn watch_contents() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let path = test_dir.path().join("config.toml");
+        std::fs::write(&path, "a").unwrap();
+
+        let mut contents = owner
+            .file(&path)
+            .unwrap()
+            .watch_contents(Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(
+            timeout(contents.next()).await.unwrap().unwrap().unwrap(),
+            b"a"
+        );
+
+        // Replaced by renaming a new file over it
+        let staged = test_dir.path().join("config.toml.tmp");
+        std::fs::write(&staged, "b").unwrap();
+        std::fs::rename(&staged, &path).unwrap();
+        assert_eq!(
+            timeout(contents.next()).await.unwrap().unwrap().unwrap(),
+            b"b"
+        );
+
+        // A burst of writes is read once it is over
+        for contents in ["c", "cd", "cde"] {
+            std::fs::write(&path, contents).unwrap();
+        }
+        assert_eq!(
+            timeout(contents.next()).await.unwrap().unwrap().unwrap(),
+            b"cde"
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), contents.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    async fn shutdown_closes_instance() {
+        use nix::sys::inotify::{InitFlags, Inotify};
+        use std::os::fd::AsRawFd;
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK).unwrap();
+        let mut owner = crate::builder()
+            .with_inotify(inotify)
+            .build_current()
+            .unwrap();
+        let test_dir = setup_testdir();
+
+        let _stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .watch()
+            .await
+            .unwrap();
+        assert!(is_watched_by(inotify.as_raw_fd(), test_dir.path()));
+
+        // The stream outlives the task, so only closing the instance removes the watch
+        owner.shutdown().await;
+        assert!(!is_watched_by(inotify.as_raw_fd(), test_dir.path()));
+    }
+
+    /// Times tearing down 10k watches, run with `cargo test --release -- --ignored --nocapture`
+    ///
+    /// Needs `fs.inotify.max_user_watches` to allow 10k more watches.
+    #[test]
+    #[ignore]
+    async fn bench_unwatch_prefix() {
+        const WATCHES: usize = 10_000;
+
+        let mut owner = crate::builder()
+            .expected_watches(WATCHES)
+            .build_current()
+            .unwrap();
+        let test_dir = setup_testdir();
+
+        let mut streams = Vec::with_capacity(WATCHES);
+        for i in 0..WATCHES {
+            let dir = test_dir.path().join(i.to_string());
+            std::fs::create_dir(&dir).unwrap();
+            streams.push(owner.dir(&dir).unwrap().create(true).watch().await.unwrap());
+        }
+
+        let start = std::time::Instant::now();
+        let removed = owner.unwatch_prefix(test_dir.path()).await.unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(removed, WATCHES);
+        println!(
+            "Removed {WATCHES} watches in {elapsed:?}, {:?} each",
+            elapsed / WATCHES as u32
+        );
+    }
+
+    #[test]
+    async fn write_complete_unset_keeps_modify() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut file = TestFile::new(test_dir.path().join("test.txt"));
+
+        let mut stream = owner
+            .file(&file.0)
+            .unwrap()
+            .modify(true)
+            .write_complete(false)
+            .watch()
+            .await
+            .unwrap();
+
+        file.change();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+    }
+
+    #[test]
+    async fn write_complete_set_then_unset() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let mut file = TestFile::new(test_dir.path().join("test.txt"));
+
+        let mut stream = owner
+            .file(&file.0)
+            .unwrap()
+            .open(true)
+            .write_complete(true)
+            .write_complete(false)
+            .watch()
+            .await
+            .unwrap();
+
+        // Only the open was asked for, so the writes are not delivered
+        file.change();
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Open)
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), stream.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    async fn rejected_flags() {
+        use crate::task::{IN_MASK_ADD, IN_MASK_CREATE};
+
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        assert!(matches!(
+            owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .raw_flags(AddWatchFlags::IN_ONESHOT)
+                .watch()
+                .await,
+            Err(WatchError::InvalidFlags(AddWatchFlags::IN_ONESHOT))
+        ));
+        assert!(matches!(
+            owner
+                .dir(test_dir.path())
+                .unwrap()
+                .create(true)
+                .raw_flags(IN_MASK_ADD | IN_MASK_CREATE)
+                .watch()
+                .await,
+            Err(WatchError::ExclusiveFlags(flags)) if flags == IN_MASK_ADD | IN_MASK_CREATE
+        ));
+        assert_eq!(owner.stats().await.unwrap().watches, 0);
+    }
+
+    #[test]
+    async fn paired_move_to_only() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let _a = TestFile::new(test_dir.path().join("a.txt"));
+
+        let mut moves = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .moves(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut moved_to = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .raw_flags(AddWatchFlags::IN_MOVED_TO)
+            .watch()
+            .await
+            .unwrap();
+
+        std::fs::rename(test_dir.path().join("a.txt"), test_dir.path().join("b.txt")).unwrap();
+
+        let renamed = timeout(moves.next()).await.unwrap().unwrap();
+        assert_eq!(renamed.event, FileWatchEvent::Moved);
+        assert_eq!(renamed.moved_to.as_deref(), Some("b.txt"));
+
+        // Never saw the IN_MOVED_FROM, so gets the IN_MOVED_TO on its own
+        let arrived = timeout(moved_to.next()).await.unwrap().unwrap();
+        assert_eq!(arrived.event, FileWatchEvent::Create);
+        assert_eq!(arrived.inner_path.as_deref(), Some("b.txt"));
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), moves.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    async fn zero_buffer() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut shared = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .buffer(0)
+            .shared()
+            .await
+            .unwrap();
+        let mut stream = owner
+            .dir(test_dir.path())
+            .unwrap()
+            .create(true)
+            .buffer(0)
+            .watch()
+            .await
+            .unwrap();
+        assert_eq!(stream.capacity(), 1);
+
+        let _file = TestFile::new(test_dir.path().join("test.txt"));
+        assert!(timeout(shared.next()).await.unwrap().unwrap().is_ok());
+        assert!(timeout(stream.next()).await.unwrap().is_some());
+    }
 }
@@ -0,0 +1,132 @@
+//! A bounded channel which drops its oldest events, rather than new ones, once full
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Debug)]
+struct Shared<T> {
+    events: VecDeque<T>,
+    capacity: usize,
+    waker: Option<Waker>,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct RingSender<T>(Arc<Mutex<Shared<T>>>);
+
+#[derive(Debug)]
+pub(crate) struct RingReceiver<T>(Arc<Mutex<Shared<T>>>);
+
+/// Create a ring channel which holds at most `capacity` events
+///
+/// # Panics
+///
+/// If `capacity` is zero
+pub(crate) fn channel<T>(capacity: usize) -> (RingSender<T>, RingReceiver<T>) {
+    assert!(capacity > 0, "ring channel requires capacity > 0");
+
+    let shared = Arc::new(Mutex::new(Shared {
+        events: VecDeque::with_capacity(capacity),
+        capacity,
+        waker: None,
+        sender_closed: false,
+        receiver_closed: false,
+    }));
+
+    (RingSender(shared.clone()), RingReceiver(shared))
+}
+
+fn lock<T>(shared: &Mutex<Shared<T>>) -> MutexGuard<'_, Shared<T>> {
+    // Nothing panics while holding the lock, so the data is still consistent
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<T> RingSender<T> {
+    /// Push `event`, returning weather an older event was dropped to make room for it
+    ///
+    /// Gives `event` back if the receiver has been dropped.
+    pub(crate) fn push(&self, event: T) -> Result<bool, T> {
+        let mut shared = lock(&self.0);
+
+        if shared.receiver_closed {
+            return Err(event);
+        }
+
+        let dropped = shared.events.len() >= shared.capacity;
+        if dropped {
+            shared.events.pop_front();
+        }
+        shared.events.push_back(event);
+
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+
+        Ok(dropped)
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        lock(&self.0).receiver_closed
+    }
+}
+
+impl<T> Drop for RingSender<T> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.0);
+        shared.sender_closed = true;
+
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> RingReceiver<T> {
+    /// Change how many events are held, dropping the oldest if there are now too many
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero
+    pub(crate) fn resize(&self, capacity: usize) {
+        assert!(capacity > 0, "ring channel requires capacity > 0");
+
+        let mut shared = lock(&self.0);
+        let excess = shared.events.len().saturating_sub(capacity);
+        shared.events.drain(..excess);
+        shared.capacity = capacity;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        lock(&self.0).events.len()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        lock(&self.0).capacity
+    }
+
+    /// Take the oldest event, or `None` once the sender is dropped and every event taken
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut shared = lock(&self.0);
+
+        match shared.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if shared.sender_closed => Poll::Ready(None),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for RingReceiver<T> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.0);
+        shared.receiver_closed = true;
+        shared.events.clear();
+    }
+}
@@ -0,0 +1,154 @@
+//! Writing events to a log without a consumer loop, see [`log_to`]
+
+use std::{fmt::Write as _, time::Duration};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::futures::{DirectoryWatchEvent, FileWatchEvent, WatchKind};
+
+/// How long written events may sit in the sink before it is flushed
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How each event is written by [`log_to`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// One JSON object per line, such as
+    /// `{"path":"a.txt","event":"create","is_dir":false,"watch":"directory"}`
+    ///
+    /// `moved_to`, `writable`, and `error` are only included for the events which carry them,
+    /// and `time`, in seconds since the unix epoch, only with a wall clock
+    /// [`timestamp`][`crate::builder::Builder::timestamps`].
+    #[default]
+    Jsonl,
+    /// Each event's [`Display`][`std::fmt::Display`] form, one per line
+    Text,
+}
+
+impl LogFormat {
+    /// Append `event` to `line`, ending with a newline
+    fn write(self, event: &DirectoryWatchEvent, line: &mut String) {
+        match self {
+            LogFormat::Jsonl => write_json(event, line),
+            LogFormat::Text => {
+                let _ = writeln!(line, "{event}");
+            }
+        }
+    }
+}
+
+fn write_json(event: &DirectoryWatchEvent, line: &mut String) {
+    line.push_str("{\"path\":");
+    match event.inner_path {
+        Some(ref path) => write_json_str(path, line),
+        None => line.push_str("null"),
+    }
+
+    let kind = match event.event {
+        FileWatchEvent::Read => "read",
+        FileWatchEvent::Write => "write",
+        FileWatchEvent::Open => "open",
+        FileWatchEvent::Close { .. } => "close",
+        FileWatchEvent::Create => "create",
+        FileWatchEvent::Delete => "delete",
+        FileWatchEvent::Metadata => "metadata",
+        FileWatchEvent::Moved => "moved",
+        FileWatchEvent::Heartbeat => "heartbeat",
+        FileWatchEvent::Error(_) => "error",
+    };
+    let _ = write!(line, ",\"event\":\"{kind}\"");
+
+    if let Some(ref to) = event.moved_to {
+        line.push_str(",\"moved_to\":");
+        write_json_str(to, line);
+    }
+    match event.event {
+        FileWatchEvent::Close { writable } => {
+            let _ = write!(line, ",\"writable\":{writable}");
+        }
+        FileWatchEvent::Error(e) => {
+            line.push_str(",\"error\":");
+            write_json_str(&e.to_string(), line);
+        }
+        _ => {}
+    }
+
+    let watch = match event.watch_kind {
+        WatchKind::File => "file",
+        WatchKind::Directory => "directory",
+    };
+    let _ = write!(line, ",\"is_dir\":{},\"watch\":\"{watch}\"", event.is_dir);
+
+    if let Some(since) = event
+        .timestamp
+        .wall()
+        .and_then(|wall| wall.duration_since(std::time::UNIX_EPOCH).ok())
+    {
+        let _ = write!(line, ",\"time\":{}", since.as_secs_f64());
+    }
+
+    line.push_str("}\n");
+}
+
+fn write_json_str(s: &str, line: &mut String) {
+    line.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// Write every event from `events` to `sink` in `format`, on a task spawned onto the current
+/// runtime
+///
+/// The sink is flushed within [`FLUSH_INTERVAL`] of an event being written, and once `events`
+/// ends, after which the task returns it. The task stops at the first error from the sink.
+pub fn log_to<S, W>(events: S, mut sink: W, format: LogFormat) -> JoinHandle<std::io::Result<W>>
+where
+    S: Stream<Item = DirectoryWatchEvent> + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        tokio::pin!(events);
+        let mut line = String::new();
+        let mut flush_at = None;
+
+        loop {
+            let event = tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)),
+                    if flush_at.is_some() =>
+                {
+                    sink.flush().await?;
+                    flush_at = None;
+                    continue;
+                }
+            };
+
+            line.clear();
+            format.write(&event, &mut line);
+            sink.write_all(line.as_bytes()).await?;
+            flush_at.get_or_insert_with(|| Instant::now() + FLUSH_INTERVAL);
+        }
+
+        sink.flush().await?;
+        Ok(sink)
+    })
+}
@@ -0,0 +1,58 @@
+//! Point in time views of the watcher task's state, for debugging
+
+use std::path::{Path, PathBuf};
+
+use crate::futures::WatchId;
+
+/// Every inotify watch held by the watcher task, see [`Handle::dump`][`crate::handle::Handle::dump`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RegistrySnapshot {
+    pub watches: Vec<WatchSnapshot>,
+}
+
+impl RegistrySnapshot {
+    /// The watches which [`Handle::unwatch_prefix`][`crate::handle::Handle::unwatch_prefix`]
+    /// would remove for `prefix`
+    ///
+    /// Paths are compared by whole components, so `/data/project` does not match
+    /// `/data/project-2`.
+    pub fn under<'a>(&'a self, prefix: &'a Path) -> impl Iterator<Item = &'a WatchSnapshot> {
+        self.watches
+            .iter()
+            .filter(move |watch| watch.path.starts_with(prefix))
+    }
+}
+
+/// A single inotify watch, and the watchers interested in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct WatchSnapshot {
+    /// The path the watch was installed on
+    pub path: PathBuf,
+    /// The mask the watch was installed with
+    pub mask: u32,
+    pub watchers: Vec<WatcherSnapshot>,
+}
+
+/// A single [`WatchRequest`][`crate::handle::WatchRequest`] which has been dispatched
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct WatcherSnapshot {
+    pub id: WatchId,
+    /// The events this watcher is interested in
+    pub flags: u32,
+    /// Weather this is a directory watch
+    pub dir: bool,
+    /// Weather this watcher will only receive a single event
+    pub once: bool,
+    /// Weather this watcher can no longer receive events, and is waiting to be removed
+    pub closed: bool,
+    /// Number of events handed to this watcher over its lifetime, including heartbeats and errors
+    pub delivered: u64,
+    /// Number of events this watcher missed because its buffer was full
+    ///
+    /// For [`Overflow::DropOldest`][`crate::handle::Overflow::DropOldest`] these were delivered
+    /// first, and then pushed out by newer events.
+    pub dropped: u64,
+}
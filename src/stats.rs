@@ -0,0 +1,151 @@
+//! Counters kept by the watcher task, for tuning buffer sizes and diagnosing load
+//!
+//! With the `metrics` feature enabled the same counters are also reported through the
+//! [`metrics`](https://docs.rs/metrics) facade:
+//!
+//! | Name               | Kind    | Description                                        |
+//! |--------------------|---------|----------------------------------------------------|
+//! | `anotify.watches`  | gauge   | Number of inotify watches currently installed      |
+//! | `anotify.reads`    | counter | `read` calls made on the inotify instance          |
+//! | `anotify.events`   | counter | Events read from inotify                           |
+//! | `anotify.dropped`  | counter | Events dropped because a stream's buffer was full  |
+//!
+//! No labels are attached.
+
+use crate::futures::FileWatchEvent;
+
+/// Snapshot of the watcher task's counters, see [`Handle::stats`][`crate::handle::Handle::stats`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Number of inotify watches currently installed
+    pub watches: usize,
+
+    /// Total number of `read` calls made on the inotify instance
+    pub read_calls: u64,
+
+    /// Total number of events read from inotify
+    pub events: u64,
+
+    /// Total number of events dropped because a stream's buffer was full
+    pub dropped: u64,
+
+    /// Distribution of the number of events read from inotify at once
+    pub batches: BatchSizes,
+
+    /// Number of events read from inotify of each kind
+    pub kinds: EventKinds,
+}
+
+/// Number of events seen of each kind, see [`FileWatchEvent`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventKinds {
+    pub read: u64,
+    pub write: u64,
+    pub open: u64,
+    pub close: u64,
+    pub create: u64,
+    pub delete: u64,
+    pub metadata: u64,
+    pub moved: u64,
+}
+
+impl EventKinds {
+    fn note(&mut self, event: &FileWatchEvent) {
+        let count = match event {
+            FileWatchEvent::Read => &mut self.read,
+            FileWatchEvent::Write => &mut self.write,
+            FileWatchEvent::Open => &mut self.open,
+            FileWatchEvent::Close { .. } => &mut self.close,
+            FileWatchEvent::Create => &mut self.create,
+            FileWatchEvent::Delete => &mut self.delete,
+            FileWatchEvent::Metadata => &mut self.metadata,
+            FileWatchEvent::Moved => &mut self.moved,
+            // Sent by the task, so never read from inotify
+            FileWatchEvent::Heartbeat | FileWatchEvent::Error(_) => return,
+        };
+
+        *count += 1;
+    }
+}
+
+/// Running summary of the sizes of event batches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchSizes {
+    /// Number of batches seen
+    pub count: u64,
+    /// Smallest batch seen, or zero if none have been seen
+    pub min: usize,
+    /// Largest batch seen
+    pub max: usize,
+    /// Sum of all batch sizes
+    pub total: u64,
+}
+
+impl BatchSizes {
+    /// The mean batch size, if any batches have been seen
+    pub fn average(&self) -> Option<f64> {
+        (self.count != 0).then(|| self.total as f64 / self.count as f64)
+    }
+
+    fn note(&mut self, size: usize) {
+        self.min = if self.count == 0 {
+            size
+        } else {
+            self.min.min(size)
+        };
+        self.max = self.max.max(size);
+        self.count += 1;
+        self.total += size as u64;
+    }
+}
+
+impl Stats {
+    /// The mean number of events returned by each `read`, if any have been made
+    ///
+    /// Values close to one mean the task is woken for nearly every event, so is likely bound by
+    /// syscalls rather than by handling events.
+    pub fn events_per_read(&self) -> Option<f64> {
+        (self.read_calls != 0).then(|| self.events as f64 / self.read_calls as f64)
+    }
+
+    pub(crate) fn note_read(&mut self) {
+        self.read_calls += 1;
+
+        #[cfg(feature = "metrics")]
+        metrics_impl::counter!("anotify.reads").increment(1);
+    }
+
+    pub(crate) fn note_watches(&mut self, count: usize) {
+        self.watches = count;
+
+        #[cfg(feature = "metrics")]
+        metrics_impl::gauge!("anotify.watches").set(count as f64);
+    }
+
+    pub(crate) fn note_dropped(&mut self) {
+        self.dropped += 1;
+
+        #[cfg(feature = "metrics")]
+        metrics_impl::counter!("anotify.dropped").increment(1);
+    }
+
+    pub(crate) fn note_kind(&mut self, event: &FileWatchEvent) {
+        self.kinds.note(event);
+    }
+
+    pub(crate) fn note_events(&mut self, count: usize) {
+        self.events += count as u64;
+        self.batches.note(count);
+
+        #[cfg(feature = "metrics")]
+        metrics_impl::counter!("anotify.events").increment(count as u64);
+
+        crate::trace!(
+            batch = count,
+            batch.min = self.batches.min,
+            batch.max = self.batches.max,
+            events = self.events,
+            "Read event batch"
+        );
+    }
+}
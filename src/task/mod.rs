@@ -1,24 +1,127 @@
-use std::{collections::HashMap, ffi::OsString, path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    ffi::OsString,
+    future::Future,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
 
-use displaydoc::Display;
 use nix::{
     errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
     sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
 };
-use thiserror::Error;
 use tokio::io::Interest;
 use tokio::{
     io::unix::{AsyncFd, AsyncFdReadyGuard},
     select,
+    sync::broadcast::{Receiver as BroadcastRecv, Sender as BroadcastSend},
     sync::mpsc::Receiver as MpscRecv,
+    sync::mpsc::UnboundedReceiver as UnboundedRecv,
     sync::mpsc::{error::TrySendError, Sender as MpscSend},
     sync::oneshot::Receiver as OnceRecv,
     sync::oneshot::Sender as OnceSend,
     task::JoinHandle,
-    time::{interval, Interval},
+    time::{interval, sleep_until, Instant, Interval},
+};
+
+use crate::{
+    builder::TimestampMode,
+    error::InitError,
+    futures::{
+        DirectoryWatchEvent, EndReason, EventError, FileWatchEvent, Replay, WatchId, WatchKind,
+    },
+    handle::{Once, PathStyle, ResourceLimit, ShutdownReport},
+    ring::RingSender,
+    snapshot::{RegistrySnapshot, WatchSnapshot, WatcherSnapshot},
+    stats::Stats,
+    trace,
 };
 
-use crate::{error::InitError, futures::DirectoryWatchEvent, trace};
+/// Identifies a single watcher, and the inotify watch it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct WatchToken {
+    pub(crate) wd: WatchDescriptor,
+    pub(crate) id: u64,
+}
+
+/// What a new subscription to an existing shared watcher needs
+pub(crate) type Subscription = (
+    WatchToken,
+    BroadcastRecv<DirectoryWatchEvent>,
+    Arc<OnceLock<EndReason>>,
+);
+
+/// Sent to shut the task down, with somewhere to report what it was still watching if wanted
+pub(crate) type ShutdownSignal = Option<OnceSend<ShutdownReport>>;
+
+/// Settings for a single watcher which are handled by the task rather than inotify
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct WatchOptions {
+    pub(crate) raw_mask: bool,
+    pub(crate) expire: Option<Duration>,
+    pub(crate) only_dirs: bool,
+    pub(crate) only_files: bool,
+    /// For file watches registered on their parent directory, the name of the file within it
+    pub(crate) name: Option<Arc<str>>,
+    /// Register a file watch on its parent directory by name when dispatched, so that it
+    /// outlives the file
+    pub(crate) follow_name: bool,
+    pub(crate) heartbeat: Option<Duration>,
+    pub(crate) settle: Option<Duration>,
+    /// End the stream rather than dropping events once its buffer is full
+    pub(crate) close_on_overflow: bool,
+    /// Names of the only entries to deliver events for, if set
+    pub(crate) names: Option<HashSet<Arc<str>>>,
+    /// Deliver metadata changes and moves of a watched directory itself
+    pub(crate) self_events: bool,
+    /// Deliver recoverable errors as events
+    pub(crate) errors: bool,
+    pub(crate) path_style: PathStyle,
+    /// Fail rather than change the mask of a watch installed through another path to the inode
+    pub(crate) exclusive: bool,
+    pub(crate) once: Once,
+    /// Hold back writes until the file is closed
+    pub(crate) write_complete: bool,
+    /// Remove the watcher once it has delivered this many events
+    pub(crate) limit: Option<u64>,
+    /// Number of recent events to number and keep for replay, if any
+    pub(crate) history: usize,
+}
+
+impl WatchOptions {
+    /// Weather events for the entry `name` pass the watch's entry filters
+    pub(crate) fn wants_entry(&self, name: Option<&Arc<str>>, is_dir: bool) -> bool {
+        if self.only_dirs && !is_dir || self.only_files && is_dir {
+            return false;
+        }
+
+        match self.names {
+            Some(ref names) => name.is_some_and(|name| names.contains(name)),
+            None => true,
+        }
+    }
+}
+
+/// `IN_MASK_ADD`, which nix does not define
+///
+/// Adds to the mask of an existing watch on the inode rather than replacing it, so that other
+/// watchers never miss events in between.
+// SAFETY: inotify has accepted this bit since it was introduced
+pub(crate) const IN_MASK_ADD: AddWatchFlags =
+    unsafe { AddWatchFlags::from_bits_unchecked(0x2000_0000) };
+
+/// `IN_MASK_CREATE`, which nix does not define
+///
+/// Makes `inotify_add_watch` fail with `EEXIST` rather than modify an existing watch.
+// SAFETY: inotify accepts this bit since Linux 4.18, and older kernels reject it with `EINVAL`
+pub(crate) const IN_MASK_CREATE: AddWatchFlags =
+    unsafe { AddWatchFlags::from_bits_unchecked(0x1000_0000) };
 
 #[derive(Debug)]
 pub(crate) enum WatchRequestInner {
@@ -26,61 +129,349 @@ pub(crate) enum WatchRequestInner {
         path: PathBuf,
         flags: AddWatchFlags,
         dir: bool,
+        options: WatchOptions,
         sender: Sender,
-        watch_token_tx: OnceSend<WatchDescriptor>,
+        end: Arc<OnceLock<EndReason>>,
+        watch_token_tx: OnceSend<Result<WatchToken, Errno>>,
+    },
+
+    /// Subscribe to an existing shared watcher with exactly this configuration, or start a new
+    /// one with `sender` if there isn't one
+    ///
+    /// `subscribed` is dropped without a reply when the new watcher is started instead, which then
+    /// reports through `watch_token_tx`
+    Subscribe {
+        path: PathBuf,
+        flags: AddWatchFlags,
+        dir: bool,
+        options: WatchOptions,
+        sender: BroadcastSend<DirectoryWatchEvent>,
+        end: Arc<OnceLock<EndReason>>,
+        subscribed: OnceSend<Subscription>,
+        watch_token_tx: OnceSend<Result<WatchToken, Errno>>,
+    },
+
+    /// Replace the sender of a stream watch, so that its buffer can be resized
+    ///
+    /// Replies with whether the watcher was still attached
+    Resize {
+        token: WatchToken,
+        sender: MpscSend<DirectoryWatchEvent>,
+        done: OnceSend<bool>,
+    },
+
+    /// Change the events a single watcher is interested in
+    Interest {
+        token: WatchToken,
+        add: AddWatchFlags,
+        remove: AddWatchFlags,
+        done: OnceSend<Result<(), Errno>>,
+    },
+
+    /// Move a watcher onto another path, reporting its new token if it still existed
+    Rewatch {
+        token: WatchToken,
+        path: PathBuf,
+        done: OnceSend<Result<Option<WatchToken>, Errno>>,
+    },
+
+    /// Stop or resume delivering events to a single watcher, reporting weather it still existed
+    Mute {
+        token: WatchToken,
+        muted: bool,
+        done: OnceSend<bool>,
     },
 
-    /// A watcher was dropped, so we should scan for it and remove it
-    #[allow(unused)]
-    Drop,
+    /// Remove every watch on a path under the prefix, reporting how many watchers were removed
+    UnwatchPrefix {
+        prefix: PathBuf,
+        done: OnceSend<usize>,
+    },
+
+    /// Report weather there is an inotify watch on the path
+    IsWatching { path: PathBuf, done: OnceSend<bool> },
+
+    /// Report a snapshot of the task's counters
+    Stats(OnceSend<Stats>),
+
+    /// Report the flags the inotify instance is open with
+    InitFlags(OnceSend<InitFlags>),
+
+    /// Report the events a watcher has kept since `after`, if it keeps any
+    Replay {
+        id: WatchId,
+        after: u64,
+        done: OnceSend<Option<Replay>>,
+    },
+
+    /// Report a snapshot of every watch
+    Dump(OnceSend<RegistrySnapshot>),
+
+    /// Exit the task without dropping its state, so that it can be launched elsewhere
+    Detach(OnceSend<Box<WatcherState>>),
+
+    /// Exit the task as if inotify had returned `Errno`
+    #[cfg(test)]
+    Fail(Errno),
+
+    /// Report a recoverable error to every watch, as if it had happened
+    #[cfg(test)]
+    Recoverable(EventError),
+
+    /// Fail the next attempts to install an inotify watch with these errors, in order
+    #[cfg(test)]
+    FailCreates(Vec<Errno>),
+}
+
+/// How to retry installing watches which hit a system limit, see
+/// [`Builder::create_retry`][`crate::builder::Builder::create_retry`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CreateRetry {
+    pub(crate) attempts: u32,
+    pub(crate) base_delay: Duration,
+}
+
+/// A watcher which has not been installed yet
+#[derive(Debug)]
+struct PendingStart {
+    path: PathBuf,
+    watch: Box<SingleWatch>,
+    /// Number of failed attempts to install it so far
+    attempts: u32,
+    watch_token_tx: OnceSend<Result<WatchToken, Errno>>,
+}
+
+/// The task's inotify instance, which is closed once dropped
+///
+/// nix's `Inotify` is a plain copyable fd, so nothing else would close it, and closing it is
+/// what has the kernel remove every watch on it.
+#[derive(Debug)]
+struct Instance(Inotify);
+
+impl std::ops::Deref for Instance {
+    type Target = Inotify;
+
+    fn deref(&self) -> &Inotify {
+        &self.0
+    }
+}
+
+impl AsRawFd for Instance {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Drop for Instance {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn drop(&mut self) {
+        if let Err(e) = nix::unistd::close(self.0.as_raw_fd()) {
+            crate::warn!("Could not close the inotify instance: {e}");
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct WatcherState {
-    instance: AsyncFd<Inotify>,
+    instance: AsyncFd<Instance>,
     request_rx: MpscRecv<WatchRequestInner>,
-    shutdown: OnceRecv<()>,
+    drop_rx: UnboundedRecv<WatchToken>,
+    shutdown: OnceRecv<ShutdownSignal>,
     clean_interval: Option<Interval>,
     watches: Watches,
+
+    /// Shared with every handle, set if the task exits because of an error
+    fatal: Arc<OnceLock<Errno>>,
+
+    /// Identifies this instance in its span and task name
+    name: String,
+
+    /// Dropped along with the task, whether it exits or is aborted
+    exit_guard: Option<OnceSend<()>>,
+
+    /// Notified once the task has started handling requests
+    ready: Option<OnceSend<()>>,
+
+    /// Where to send this state once the task exits, if detaching
+    detach: Option<OnceSend<Box<WatcherState>>>,
 }
 
 impl WatcherState {
+    /// Create the task's state, on `inotify` if given or a new instance otherwise
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         request_rx: MpscRecv<WatchRequestInner>,
-        shutdown: OnceRecv<()>,
+        drop_rx: UnboundedRecv<WatchToken>,
+        shutdown: OnceRecv<ShutdownSignal>,
+        fatal: Arc<OnceLock<Errno>>,
+        inotify: Option<Inotify>,
+        name: String,
         clean_duration: Option<Duration>,
+        expected_watches: usize,
     ) -> Result<Self, InitError> {
-        let instance =
-            AsyncFd::with_interest(Inotify::init(InitFlags::IN_NONBLOCK)?, Interest::READABLE)?;
-
-        let clean_interval = clean_duration.map(|duration| {
-            let mut it = interval(duration);
-            it.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
-            it
-        });
+        let inotify = match inotify {
+            Some(inotify) => {
+                // Events are only read once the fd is readable, but reads must never block the
+                // runtime if another reader got to them first
+                let fd = inotify.as_raw_fd();
+                let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+                fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+                inotify
+            }
+            None => Inotify::init(InitFlags::IN_NONBLOCK)?,
+        };
+        let instance = AsyncFd::with_interest(Instance(inotify), Interest::READABLE)?;
 
         Ok(Self {
             instance,
             request_rx,
+            drop_rx,
             shutdown,
-            clean_interval,
-            watches: Default::default(),
+            fatal,
+            name,
+            clean_interval: clean_duration.map(clean_interval),
+            watches: Watches::with_capacity(expected_watches),
+            exit_guard: None,
+            ready: None,
+            detach: None,
         })
     }
 
-    pub fn launch(self: Box<Self>) -> JoinHandle<()> {
+    /// Number of watches which can be installed before the task's tables need to grow
+    #[cfg(test)]
+    pub(crate) fn watch_capacity(&self) -> usize {
+        self.watches
+            .watches
+            .capacity()
+            .min(self.watches.paths.capacity())
+    }
+
+    /// Move a detached or parked task's io resources onto the current runtime, so that it can be
+    /// launched there
+    ///
+    /// The inotify instance, and so all of its watches, is kept.
+    pub(crate) fn reregister(self: Box<Self>) -> Result<Box<Self>, InitError> {
+        let Self {
+            instance,
+            request_rx,
+            drop_rx,
+            shutdown,
+            fatal,
+            name,
+            clean_interval: old_interval,
+            watches,
+            exit_guard: _,
+            ready: _,
+            detach: _,
+        } = *self;
+
+        Ok(Box::new(Self {
+            instance: AsyncFd::with_interest(instance.into_inner(), Interest::READABLE)?,
+            request_rx,
+            drop_rx,
+            shutdown,
+            fatal,
+            name,
+            clean_interval: old_interval.map(|it| clean_interval(it.period())),
+            watches,
+            exit_guard: None,
+            ready: None,
+            detach: None,
+        }))
+    }
+
+    /// Hold on to `guard` until the task is dropped, so that its receiver can be notified
+    pub(crate) fn exit_guard(&mut self, guard: OnceSend<()>) {
+        self.exit_guard = Some(guard);
+    }
+
+    /// Notify `ready` once the task has started handling requests
+    pub(crate) fn ready_signal(&mut self, ready: OnceSend<()>) {
+        self.ready = Some(ready);
+    }
+
+    /// Install a watch straight away, before the task has been launched
+    ///
+    /// Watches which hit a system limit fail at once, rather than being deferred by
+    /// [`create_retry`][`WatcherState::create_retry`].
+    pub(crate) fn install_now(
+        &mut self,
+        path: PathBuf,
+        flags: AddWatchFlags,
+        dir: bool,
+        options: WatchOptions,
+        sender: Sender,
+        end: Arc<OnceLock<EndReason>>,
+    ) -> Result<WatchToken, Errno> {
+        let watch = self.watches.new_watcher(flags, dir, options, sender, end);
+        let (watch_token_tx, mut watch_token_rx) = tokio::sync::oneshot::channel();
+
+        // Nothing would be left to report a deferred watch's token
+        let create_retry = self.watches.create_retry.take();
+        self.watches.install(
+            self.instance.get_ref(),
+            PendingStart {
+                path,
+                watch: Box::new(watch),
+                attempts: 0,
+                watch_token_tx,
+            },
+        );
+        self.watches.create_retry = create_retry;
+
+        // Without retries every outcome is sent before `install` returns
+        watch_token_rx.try_recv().unwrap_or(Err(Errno::EAGAIN))
+    }
+
+    /// Retry installing watches which hit a system limit, rather than failing them at once
+    pub(crate) fn create_retry(&mut self, retry: Option<CreateRetry>) {
+        self.watches.create_retry = retry;
+    }
+
+    /// Record event timestamps from these clocks
+    pub(crate) fn timestamps(&mut self, mode: TimestampMode) {
+        self.watches.timestamps = mode;
+    }
+
+    /// Spawn the task onto the current runtime, leaving its state in `parked` if the task is
+    /// dropped along with the runtime
+    pub fn launch(self: Box<Self>, parked: Arc<Parked>) -> JoinHandle<()> {
+        let name = self.name.clone();
+        let task = self.into_future(Some(parked));
+
         cfg_if::cfg_if! {
             if #[cfg(all(tokio_unstable, feature = "tracing"))] {
                 tokio::task::Builder::new()
-                    .name("Inotify Watcher")
-                    .spawn(self.run())
+                    .name(&name)
+                    .spawn(task)
+                    .expect("could not spawn the watcher task")
             } else {
-                tokio::spawn(self.run())
+                let _ = name;
+                tokio::spawn(task)
             }
         }
     }
 
-    async fn step(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+    /// The task's event loop, within its span, for the caller to spawn or drive
+    pub(crate) fn into_future(
+        self: Box<Self>,
+        parked: Option<Arc<Parked>>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        #[cfg(feature = "tracing")]
+        let task = {
+            let name = self.name.clone();
+            tracing_impl::Instrument::instrument(
+                self.run(parked),
+                tracing_impl::info_span!("anotify_task", name = %name),
+            )
+        };
+        #[cfg(not(feature = "tracing"))]
+        let task = self.run(parked);
+
+        task
+    }
+
+    async fn step(&mut self) -> Result<bool, Errno> {
         async fn clean_wait(interval: &mut Option<Interval>) {
             match interval {
                 Some(interval) => interval.tick().await,
@@ -88,15 +479,40 @@ impl WatcherState {
             };
         }
 
+        let next_deadline = self.watches.next_deadline();
+
         select! {
             biased;
 
-            _ = &mut self.shutdown => {
+            signal = &mut self.shutdown => {
                 crate::info!("Shutting Down");
 
+                // Requests sent before shutting down are still answered, so that they are not
+                // lost depending on which the task happened to see first
+                self.request_rx.close();
+                while let Ok(request) = self.request_rx.try_recv() {
+                    if !matches!(request, WatchRequestInner::Detach(_)) {
+                        self.watches
+                            .handle_request(self.instance.get_ref(), request)
+                            .await?;
+                    }
+                }
+
+                if let Ok(Some(report)) = signal {
+                    let _ = report.send(self.watches.shutdown_report());
+                }
+
                 Ok(false)
             }
 
+            // Shutdown and drops make up the control plane, and are always taken before events
+            // and new requests so that a flood of either cannot delay teardown
+            Some(token) = self.drop_rx.recv() => {
+                self.watches.handle_drop(self.instance.get_ref(), token);
+
+                Ok(true)
+            }
+
             Ok(read_guard) = self.instance.readable() => {
                 self.watches
                     .handle_events(read_guard)
@@ -107,6 +523,13 @@ impl WatcherState {
 
             request = self.request_rx.recv() => {
                 match request {
+                    Some(WatchRequestInner::Detach(tx)) => {
+                        crate::info!("Detaching");
+                        self.detach = Some(tx);
+
+                        Ok(false)
+                    }
+
                     Some(event) => {
                         self.watches
                             .handle_request(self.instance.get_ref(), event)
@@ -123,6 +546,15 @@ impl WatcherState {
                 }
             }
 
+            _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                self.watches.expire(self.instance.get_ref());
+                self.watches.retry(self.instance.get_ref());
+                self.watches.settle();
+                self.watches.heartbeat();
+
+                Ok(true)
+            }
+
             _ = clean_wait(&mut self.clean_interval), if self.watches.dirty => {
                 crate::error!("WOKE UP FOR CLEAN");
 
@@ -134,13 +566,35 @@ impl WatcherState {
         }
     }
 
-    async fn run(mut self: Box<Self>) {
+    async fn run(self: Box<Self>, parked: Option<Arc<Parked>>) {
+        let mut guard = ParkOnDrop {
+            state: Some(self),
+            parked,
+        };
+        guard.state.as_mut().unwrap().run_loop().await;
+
+        // Exited by itself, so the state is done with unless it is being moved elsewhere
+        let mut state = guard.state.take().unwrap();
+        if let Some(tx) = state.detach.take() {
+            let _ = tx.send(state);
+        }
+    }
+
+    async fn run_loop(&mut self) {
         if let Some(ref mut tick) = self.clean_interval {
             tick.reset();
         }
 
+        if let Some(ready) = self.ready.take() {
+            let _ = ready.send(());
+        }
+
         loop {
-            match self.step().await {
+            let step = self.step().await;
+            // Whatever the step delivered, such as a batch of events, reaches each handler at once
+            self.watches.flush_batches();
+
+            match step {
                 Ok(cont) => {
                     if !cont {
                         break;
@@ -148,6 +602,12 @@ impl WatcherState {
                 }
                 Err(e) => {
                     crate::error!("Got unexpected error in event loop: {e}");
+
+                    // Set before the senders are dropped, so that every watch can see why it
+                    // ended as soon as it does
+                    let _ = self.fatal.set(e);
+                    self.watches.fail(e);
+                    self.watches.flush_batches();
                     break;
                 }
             }
@@ -155,112 +615,675 @@ impl WatcherState {
     }
 }
 
+/// Where a launched task's state is left if the task is dropped without exiting, such as when
+/// its runtime is dropped, so that it can be launched again, see `OwnedHandle::rebind`
+#[derive(Debug, Default)]
+pub(crate) struct Parked {
+    pub(crate) state: Mutex<Option<Box<WatcherState>>>,
+    /// Set before aborting the task, whose state should then be dropped instead
+    pub(crate) abandon: AtomicBool,
+}
+
+impl Parked {
+    /// Drop the task's state rather than keeping it, once the task is next dropped
+    pub(crate) fn abandon(&self) {
+        self.abandon.store(true, Ordering::Release);
+    }
+}
+
+/// Holds the task's state while it runs, and parks it if the task is dropped part way through
+struct ParkOnDrop {
+    state: Option<Box<WatcherState>>,
+    parked: Option<Arc<Parked>>,
+}
+
+impl Drop for ParkOnDrop {
+    fn drop(&mut self) {
+        let (Some(mut state), Some(parked)) = (self.state.take(), self.parked.take()) else {
+            return;
+        };
+        if parked.abandon.load(Ordering::Acquire) {
+            return;
+        }
+
+        crate::debug!("The watcher task was dropped without exiting, keeping its state");
+        // Nothing is running it any more
+        state.exit_guard = None;
+        if let Ok(mut slot) = parked.state.lock() {
+            *slot = Some(state);
+        };
+    }
+}
+
+/// The flags which `inotify` is open with, whether it was created with them or they were set
+/// afterwards
+fn init_flags(inotify: &Inotify) -> InitFlags {
+    let fd = inotify.as_raw_fd();
+    let mut flags = InitFlags::empty();
+
+    if fcntl(fd, FcntlArg::F_GETFL)
+        .is_ok_and(|status| OFlag::from_bits_truncate(status).contains(OFlag::O_NONBLOCK))
+    {
+        flags |= InitFlags::IN_NONBLOCK;
+    }
+    if fcntl(fd, FcntlArg::F_GETFD)
+        .is_ok_and(|fd_flags| FdFlag::from_bits_truncate(fd_flags).contains(FdFlag::FD_CLOEXEC))
+    {
+        flags |= InitFlags::IN_CLOEXEC;
+    }
+
+    flags
+}
+
+fn clean_interval(duration: Duration) -> Interval {
+    let mut it = interval(duration);
+    it.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    it
+}
+
 #[derive(Debug)]
 pub(crate) enum Sender {
     Once(OnceSend<DirectoryWatchEvent>),
     Stream(MpscSend<DirectoryWatchEvent>),
+    Broadcast(BroadcastSend<DirectoryWatchEvent>),
+    Ring(RingSender<DirectoryWatchEvent>),
+    Batch(BatchHandler),
     None,
 }
 
+type BatchFn = Box<dyn FnMut(&[DirectoryWatchEvent]) + Send>;
+
+/// Calls a handler with every event collected for it since the last flush
+pub(crate) struct BatchHandler {
+    handler: BatchFn,
+    pending: Vec<DirectoryWatchEvent>,
+}
+
+impl BatchHandler {
+    pub(crate) fn new(handler: impl FnMut(&[DirectoryWatchEvent]) + Send + 'static) -> Self {
+        Self {
+            handler: Box::new(handler),
+            pending: Vec::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            (self.handler)(&self.pending);
+            // Keeps the allocation for the next batch
+            self.pending.clear();
+        }
+    }
+}
+
+impl std::fmt::Debug for BatchHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BatchHandler")
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sender {
+    fn is_closed(&self) -> bool {
+        match self {
+            Sender::Once(sender) => sender.is_closed(),
+            Sender::Stream(sender) => sender.is_closed(),
+            Sender::Broadcast(sender) => sender.receiver_count() == 0,
+            Sender::Ring(sender) => sender.is_closed(),
+            // Only stops once the watch is dropped
+            Sender::Batch(_) => false,
+            Sender::None => true,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct SingleWatch {
+    id: u64,
     flags: AddWatchFlags,
     dir: bool,
+    options: WatchOptions,
     remove: bool,
     sender: Sender,
+    /// When the next heartbeat is due, if this watcher wants them
+    heartbeat_at: Option<Instant>,
+    /// Entries created recently, whose events are held until they settle
+    settling: Vec<Settling>,
+    /// Shared with the stream, so that it can tell why it ended
+    end: Arc<OnceLock<EndReason>>,
+    /// Number of deduplicated subscriptions which each need to be dropped before this is removed
+    subscribers: usize,
+    /// Drop events from inotify rather than delivering them
+    muted: bool,
+    /// Entries written to since they were last closed, see `write_complete`
+    written: HashSet<Option<Arc<str>>>,
+    /// The most recent numbered events, see `WatchOptions::history`
+    history: VecDeque<DirectoryWatchEvent>,
+    /// Sequence number of the last event numbered
+    seq: u64,
+    /// Number of events handed to the sender
+    delivered: u64,
+    /// Number of events dropped because the buffer was full
+    dropped: u64,
+}
+
+impl Drop for SingleWatch {
+    fn drop(&mut self) {
+        // Anything which removes a watcher for another reason will have already recorded it
+        let _ = self.end.set(EndReason::InstanceClosed);
+    }
+}
+
+impl SingleWatch {
+    /// The events inotify needs to report for this watcher
+    fn mask(&self) -> AddWatchFlags {
+        let mut mask = self.flags;
+        if self.options.settle.is_some() {
+            // Needed to tell when an entry is removed before it settles
+            mask |= AddWatchFlags::IN_DELETE;
+        }
+        if self.options.self_events {
+            mask |= AddWatchFlags::IN_ATTRIB | AddWatchFlags::IN_MOVE_SELF;
+        }
+        if self.options.write_complete {
+            mask |= AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_CLOSE_WRITE;
+        }
+        mask
+    }
+
+    /// The kept events numbered after `after`, or a gap if some of them are no longer kept
+    fn replay_since(&self, after: u64) -> Replay {
+        let oldest = match self.history.front() {
+            Some(event) => event.seq.unwrap_or_default(),
+            None => self.seq + 1,
+        };
+        if after.saturating_add(1) < oldest {
+            return Replay::Gap;
+        }
+
+        Replay::Events(
+            self.history
+                .iter()
+                .filter(|event| event.seq > Some(after))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Send `event` to this watcher, returning weather it should now be removed
+    fn send(&mut self, event: &DirectoryWatchEvent, stats: &mut Stats) -> bool {
+        // Kept whether or not the sender takes it, so that replay also recovers dropped events
+        let numbered;
+        let event = match self.options.history {
+            0 => event,
+            depth => {
+                self.seq += 1;
+                let mut event = event.clone();
+                event.seq = Some(self.seq);
+                if self.history.len() == depth {
+                    self.history.pop_front();
+                }
+                self.history.push_back(event.clone());
+
+                numbered = event;
+                &numbered
+            }
+        };
+
+        let mut replace = std::mem::replace(&mut self.sender, Sender::None);
+
+        replace = match replace {
+            Sender::Once(sender) => {
+                if sender.send(event.clone()).is_ok() {
+                    self.delivered += 1;
+                }
+
+                self.remove = true;
+
+                // send consumes sender, so we cannot defer drop
+                Sender::None
+            }
+            Sender::Stream(sender) => {
+                match sender.try_send(event.clone()) {
+                    Err(TrySendError::Closed(_)) => {
+                        self.remove = true;
+
+                        // we defer cleaning up the actual sender
+                    }
+                    Err(TrySendError::Full(_)) if self.options.close_on_overflow => {
+                        stats.note_dropped();
+                        self.dropped += 1;
+                        let _ = self.end.set(EndReason::Overflowed);
+                        self.remove = true;
+
+                        // Dropped now, so that the stream ends once it has taken what is buffered
+                        return true;
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        stats.note_dropped();
+                        self.dropped += 1;
+                    }
+                    Ok(()) => self.delivered += 1,
+                }
+
+                Sender::Stream(sender)
+            }
+            Sender::Broadcast(sender) => {
+                // Slow receivers lag rather than holding back the others, so this only
+                // fails once every receiver has been dropped
+                match sender.send(event.clone()) {
+                    Ok(_) => self.delivered += 1,
+                    Err(_) => self.remove = true,
+                }
+
+                Sender::Broadcast(sender)
+            }
+            Sender::Ring(sender) => {
+                match sender.push(event.clone()) {
+                    Err(_) => self.remove = true,
+                    Ok(dropped) => {
+                        self.delivered += 1;
+                        if dropped {
+                            stats.note_dropped();
+                            self.dropped += 1;
+                        }
+                    }
+                }
+
+                Sender::Ring(sender)
+            }
+            Sender::Batch(mut batch) => {
+                batch.pending.push(event.clone());
+                self.delivered += 1;
+
+                Sender::Batch(batch)
+            }
+            otherwise => otherwise,
+        };
+
+        std::mem::swap(&mut replace, &mut self.sender);
+
+        if !self.remove
+            && self
+                .options
+                .limit
+                .is_some_and(|limit| self.delivered >= limit)
+        {
+            let _ = self.end.set(EndReason::Taken);
+            self.remove = true;
+
+            // Dropped now, so that the stream ends once it has taken what is buffered
+            self.sender = Sender::None;
+        }
+
+        self.remove
+    }
+}
+
+/// Events held back for one entry of a directory until it has settled
+#[derive(Debug)]
+struct Settling {
+    name: Arc<str>,
+    until: Instant,
+    events: Vec<DirectoryWatchEvent>,
+}
+
+/// Send `event` to `watcher`, pushing back its next heartbeat, and return weather it should now
+/// be removed
+fn deliver(
+    watcher: &mut SingleWatch,
+    event: &DirectoryWatchEvent,
+    wd: WatchDescriptor,
+    stats: &mut Stats,
+    heartbeats: &mut BTreeMap<(Instant, u64), WatchToken>,
+) -> bool {
+    if watcher.send(event, stats) {
+        return true;
+    }
+
+    if let (Some(at), Some(interval)) = (watcher.heartbeat_at, watcher.options.heartbeat) {
+        // Only real events push back the next heartbeat
+        let next = Instant::now() + interval;
+        heartbeats.remove(&(at, watcher.id));
+        heartbeats.insert((next, watcher.id), WatchToken { wd, id: watcher.id });
+        watcher.heartbeat_at = Some(next);
+    }
+
+    false
+}
+
+/// The name of an entry, if it is valid UTF-8
+fn entry_name(name: Option<OsString>) -> Option<Arc<str>> {
+    name.map(OsString::into_string)
+        .and_then(Result::ok)
+        .map(Into::into)
+}
+
+/// Build the path reported for the entry `name` in the watched directory `dir`
+fn styled(style: PathStyle, dir: &Path, name: &Option<Arc<str>>) -> Option<Arc<str>> {
+    match style {
+        PathStyle::Bare | PathStyle::RelativeToWatch => name.clone(),
+        PathStyle::JoinedWithWatch => name
+            .as_deref()
+            .map(|name| dir.join(name).to_string_lossy().into()),
+    }
 }
 
 #[derive(Debug)]
 struct WatchState {
     path: PathBuf,
+    mask: AddWatchFlags,
     watchers: Vec<SingleWatch>,
+
+    /// Entered while handling events for this watch, so that logs can be attributed to it
+    #[cfg(feature = "tracing")]
+    span: tracing_impl::Span,
 }
 
 #[derive(Debug, Default)]
 struct Watches {
     watches: HashMap<WatchDescriptor, WatchState>,
     paths: HashMap<PathBuf, WatchDescriptor>,
+    stats: Stats,
+    next_id: u64,
+    /// Watchers to remove once their deadline has passed
+    expiries: BTreeMap<(Instant, u64), WatchToken>,
+    /// Watchers to send a heartbeat to once their deadline has passed
+    heartbeats: BTreeMap<(Instant, u64), WatchToken>,
+    /// Watchers holding events for an entry which will have settled once the deadline has passed
+    settles: BTreeMap<(Instant, u64), WatchToken>,
+    /// Watchers to try installing again once their deadline has passed
+    retries: BTreeMap<(Instant, u64), PendingStart>,
+    create_retry: Option<CreateRetry>,
+    timestamps: TimestampMode,
+    /// Watches which have, or had, a watcher with a batch handler
+    batches: HashSet<WatchDescriptor>,
+    #[cfg(test)]
+    fail_creates: Vec<Errno>,
     pub dirty: bool,
 }
 
 impl Watches {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            watches: HashMap::with_capacity(capacity),
+            paths: HashMap::with_capacity(capacity),
+            ..Default::default()
+        }
+    }
+
+    /// Hand each batch handler every event collected for it since the last flush, in one call
+    fn flush_batches(&mut self) {
+        let watches = &mut self.watches;
+
+        self.batches.retain(|wd| {
+            let Some(state) = watches.get_mut(wd) else {
+                return false;
+            };
+
+            let mut batched = false;
+            for watcher in state.watchers.iter_mut() {
+                if let Sender::Batch(ref mut batch) = watcher.sender {
+                    batch.flush();
+                    batched = true;
+                }
+            }
+
+            batched
+        });
+    }
+
+    /// Dispatch a batch of events to the interested watchers
+    ///
+    /// Events are handled strictly in the order the kernel reported them, so every watcher sees
+    /// its own events in kernel order. Anything which coalesces events (such as pairing moves)
+    /// must preserve this.
     async fn handle_events(
         &mut self,
-        mut guard: AsyncFdReadyGuard<'_, Inotify>,
+        mut guard: AsyncFdReadyGuard<'_, Instance>,
     ) -> Result<(), Errno> {
         trace!("Processing Events from Watches");
 
         // This should be infallable because we set the FD to non-blocking
         //   and we were woken by the executor with readable
+        self.stats.note_read();
         let events = guard.get_inner().read_events()?;
+        self.stats.note_events(events.len());
+        let timestamp = self.timestamps.now();
+
+        // The cookie of the last IN_MOVED_FROM, if it was paired with the event after it
+        let mut paired_cookie = None;
+        // Watchers which were given the last IN_MOVED_FROM along with its new name
+        let mut renamed_by = Vec::new();
+        let mut taken = Vec::new();
+        let mut events = events.into_iter().peekable();
 
-        for event in events.into_iter() {
+        while let Some(event) = events.next() {
             trace!("Got Event");
             let flags = event.mask;
-            let path = event.name.map(OsString::into_string).and_then(Result::ok);
+            let event_wd = event.wd;
+            let cookie = event.cookie;
+            let path = entry_name(event.name);
 
-            if let Some(watch) = self.watches.get_mut(&event.wd) {
-                trace!(
-                    "Got event for path: {} with flags {flags:4X}",
-                    watch.path.display()
+            // A rename within one directory is reported as an IN_MOVED_FROM immediately followed
+            // by an IN_MOVED_TO with the same cookie, unless a read happened to split them
+            let renamed_to = if flags.contains(AddWatchFlags::IN_MOVED_FROM) {
+                events
+                    .peek()
+                    .filter(|next| {
+                        next.wd == event_wd
+                            && next.cookie == cookie
+                            && next.mask.contains(AddWatchFlags::IN_MOVED_TO)
+                    })
+                    .map(|next| entry_name(next.name.clone()))
+            } else {
+                None
+            };
+            let paired =
+                flags.contains(AddWatchFlags::IN_MOVED_TO) && paired_cookie == Some(cookie);
+            paired_cookie = renamed_to.is_some().then_some(cookie);
+            let renamed_before = std::mem::take(&mut renamed_by);
+
+            // Not for any one watch, as the kernel cannot tell which lost events were for which
+            if flags.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                crate::warn!("inotify event queue overflowed");
+                self.report(None, EventError::QueueOverflow);
+                continue;
+            }
+
+            // Sent just before the watch is removed, so that it can be told apart from a delete
+            if flags.contains(AddWatchFlags::IN_UNMOUNT) {
+                for watcher in self.watchers(event_wd) {
+                    let _ = watcher.end.set(EndReason::Unmounted);
+                }
+                continue;
+            }
+
+            // The kernel removes the watch once the inode is deleted or its filesystem unmounted
+            if flags.contains(AddWatchFlags::IN_IGNORED) {
+                self.ignored(event_wd);
+                continue;
+            }
+
+            if let Some(watch) = self.watches.get_mut(&event.wd) {
+                #[cfg(feature = "tracing")]
+                let _span = watch.span.enter();
+
+                trace!(
+                    "Got event for path: {} with flags {flags:4X}",
+                    watch.path.display()
                 );
 
-                let event = flags.try_into();
-                if event.is_err() {
+                let moved_from = flags.contains(AddWatchFlags::IN_MOVED_FROM);
+
+                let Some(kind) = FileWatchEvent::from_mask(flags) else {
                     trace!("Got unexpected Flags: 0x{flags:8X}");
                     continue;
-                }
+                };
 
-                let event = DirectoryWatchEvent {
+                self.stats.note_kind(&kind);
+
+                let mut event = DirectoryWatchEvent {
                     inner_path: path.clone(),
-                    event: event.unwrap(),
+                    event: kind,
+                    is_dir: flags.contains(AddWatchFlags::IN_ISDIR),
+                    raw_mask: None,
+                    watch_kind: WatchKind::File,
+                    moved_to: None,
+                    seq: None,
+                    timestamp,
                 };
 
                 for watcher in watch.watchers.iter_mut() {
-                    if watcher.remove {
+                    if watcher.remove || watcher.muted {
                         continue;
                     }
-                    if !watcher.dir && path.is_some() {
+                    // File watches only see events without a name, unless they follow a file
+                    // by name through its parent directory
+                    if !watcher.dir && path != watcher.options.name {
                         continue;
                     }
-
-                    if !flags.intersects(watcher.flags) {
+                    // Already delivered along with the IN_MOVED_FROM it was paired with, while
+                    // watchers which did not capture that still see this on its own
+                    if paired && renamed_before.contains(&watcher.id) {
+                        continue;
+                    }
+                    if !watcher.options.wants_entry(path.as_ref(), event.is_dir) {
                         continue;
                     }
 
-                    // We know that this is an event that they want
-                    // So take the sender, send, and replace the sender if necessary
-
-                    let mut replace = std::mem::replace(&mut watcher.sender, Sender::None);
-
-                    replace = match replace {
-                        Sender::Once(sender) => {
-                            let _ = sender.send(event.clone());
+                    let settling = watcher.options.settle.is_some()
+                        && path
+                            .as_ref()
+                            .is_some_and(|name| watcher.settling.iter().any(|it| it.name == *name));
 
-                            watcher.remove = true;
-                            self.dirty = true;
+                    // An entry deleted while settling never existed as far as the watcher is
+                    // concerned, so drop everything held for it along with the delete
+                    if settling && event.event == FileWatchEvent::Delete {
+                        watcher
+                            .settling
+                            .retain(|it| Some(&it.name) != path.as_ref());
+                        continue;
+                    }
 
-                            // send consumes sender, so we cannot defer drop
-                            Sender::None
+                    // Writes are held back and delivered in place of the close which ends them
+                    let mut completed = false;
+                    if watcher.options.write_complete {
+                        match kind {
+                            FileWatchEvent::Write => {
+                                watcher.written.insert(path.clone());
+                                continue;
+                            }
+                            FileWatchEvent::Close { writable: true } => {
+                                completed = watcher.written.remove(&path);
+                            }
+                            FileWatchEvent::Delete => {
+                                watcher.written.remove(&path);
+                            }
+                            _ => {}
                         }
-                        Sender::Stream(sender) => {
-                            if let Err(TrySendError::Closed(_)) = sender.try_send(event.clone()) {
-                                watcher.remove = true;
-                                self.dirty = true;
+                    }
 
-                                // we defer cleaning up the actual sender
-                            }
+                    // Changes to a directory itself are only delivered when asked for, as the
+                    // flags for them also select changes to its entries
+                    let wanted = if watcher.dir
+                        && path.is_none()
+                        && matches!(
+                            event.event,
+                            FileWatchEvent::Metadata | FileWatchEvent::Moved
+                        ) {
+                        watcher.options.self_events
+                    } else {
+                        flags.intersects(watcher.flags)
+                            || watcher.options.once == Once::Any
+                                && matches!(watcher.sender, Sender::Once(_))
+                    };
+                    if !wanted && !completed {
+                        continue;
+                    }
 
-                            Sender::Stream(sender)
+                    // We know that this is an event that they want
+                    event.raw_mask = watcher.options.raw_mask.then(|| flags.bits());
+                    event.watch_kind = if watcher.dir {
+                        WatchKind::Directory
+                    } else {
+                        WatchKind::File
+                    };
+                    event.inner_path = styled(watcher.options.path_style, &watch.path, &path);
+
+                    // Watches following a file by name see it deleted when it is moved away
+                    let moved = moved_from && watcher.options.name.is_none();
+                    event.event = if moved {
+                        FileWatchEvent::Moved
+                    } else if completed {
+                        FileWatchEvent::Write
+                    } else {
+                        kind
+                    };
+                    event.moved_to = match renamed_to {
+                        Some(ref to) if moved => {
+                            styled(watcher.options.path_style, &watch.path, to)
                         }
-                        otherwise => otherwise,
+                        _ => None,
                     };
+                    if event.moved_to.is_some() {
+                        renamed_by.push(watcher.id);
+                    }
+
+                    // Hold back creates, and anything after them for the same entry, until they
+                    // have settled
+                    if settling {
+                        let name = path.as_ref().unwrap();
+                        let entry = watcher.settling.iter_mut().find(|it| it.name == *name);
+                        entry.unwrap().events.push(event.clone());
+                        continue;
+                    }
+                    if let (Some(settle), Some(name), FileWatchEvent::Create) =
+                        (watcher.options.settle, &path, event.event)
+                    {
+                        let until = Instant::now() + settle;
+                        watcher.settling.push(Settling {
+                            name: name.clone(),
+                            until,
+                            events: vec![event.clone()],
+                        });
+                        self.settles.insert(
+                            (until, watcher.id),
+                            WatchToken {
+                                wd: event_wd,
+                                id: watcher.id,
+                            },
+                        );
+                        continue;
+                    }
 
-                    std::mem::swap(&mut replace, &mut watcher.sender);
+                    if deliver(
+                        watcher,
+                        &event,
+                        event_wd,
+                        &mut self.stats,
+                        &mut self.heartbeats,
+                    ) {
+                        self.dirty = true;
+                        if watcher.options.limit.is_some() {
+                            taken.push(event_wd);
+                        }
+                    }
                 }
             }
         }
 
+        // Watchers which have delivered all they were taken for free their watch straight away
+        for wd in taken {
+            if let Some(state) = self.watches.get_mut(&wd) {
+                state
+                    .watchers
+                    .retain(|watcher| !watcher.remove && !watcher.sender.is_closed());
+                self.prune(guard.get_inner(), wd);
+            }
+        }
+
         guard.clear_ready();
         Ok(())
     }
@@ -271,43 +1294,822 @@ impl Watches {
         request: WatchRequestInner,
     ) -> Result<(), Errno> {
         match request {
-            WatchRequestInner::Drop => {
-                self.dirty = true;
+            WatchRequestInner::Stats(tx) => {
+                let _ = tx.send(self.stats.clone());
+            }
+            WatchRequestInner::InitFlags(tx) => {
+                let _ = tx.send(init_flags(inotify));
+            }
+            WatchRequestInner::Dump(tx) => {
+                let _ = tx.send(self.snapshot());
+            }
+            WatchRequestInner::Replay { id, after, done } => {
+                let replay = self
+                    .watches
+                    .values()
+                    .flat_map(|state| state.watchers.iter())
+                    .find(|watcher| {
+                        WatchId(watcher.id) == id && !watcher.remove && watcher.options.history != 0
+                    })
+                    .map(|watcher| watcher.replay_since(after));
+
+                let _ = done.send(replay);
+            }
+            WatchRequestInner::Subscribe {
+                path,
+                flags,
+                dir,
+                options,
+                sender,
+                end,
+                subscribed,
+                watch_token_tx,
+            } => {
+                if let Some(subscription) = self.subscribe(&path, flags, dir, &options) {
+                    let _ = subscribed.send(subscription);
+                    return Ok(());
+                }
+                drop(subscribed);
+
+                let watch = self.new_watcher(flags, dir, options, Sender::Broadcast(sender), end);
+
+                self.install(
+                    inotify,
+                    PendingStart {
+                        path,
+                        watch: Box::new(watch),
+                        attempts: 0,
+                        watch_token_tx,
+                    },
+                );
+            }
+            WatchRequestInner::IsWatching { path, done } => {
+                let _ = done.send(self.paths.contains_key(&path));
+            }
+            WatchRequestInner::Detach(_) => unreachable!("Detach is handled by the event loop"),
+            #[cfg(test)]
+            WatchRequestInner::Fail(errno) => return Err(errno),
+            #[cfg(test)]
+            WatchRequestInner::Recoverable(error) => self.report(None, error),
+            #[cfg(test)]
+            WatchRequestInner::FailCreates(mut errors) => {
+                errors.reverse();
+                self.fail_creates = errors;
+            }
+            WatchRequestInner::Interest {
+                token,
+                add,
+                remove,
+                done,
+            } => {
+                let mut result = Ok(());
+
+                if let Some(watcher) = self.watcher_mut(token) {
+                    let old = watcher.flags;
+                    watcher.flags.insert(add);
+                    watcher.flags.remove(remove);
+
+                    result = self.update_mask(inotify, token.wd);
+                    if result.is_err() {
+                        if let Some(watcher) = self.watcher_mut(token) {
+                            watcher.flags = old;
+                        }
+                    }
+                }
+
+                let _ = done.send(result);
+            }
+            WatchRequestInner::Resize {
+                token,
+                sender,
+                done,
+            } => {
+                let watcher = self.watcher_mut(token).filter(|watcher| !watcher.remove);
+                let found = watcher.is_some();
+                if let Some(watcher) = watcher {
+                    // Dropping the old sender lets the stream drain what was already buffered
+                    watcher.sender = Sender::Stream(sender);
+                }
+
+                let _ = done.send(found);
+            }
+            WatchRequestInner::UnwatchPrefix { prefix, done } => {
+                let _ = done.send(self.unwatch_prefix(inotify, &prefix));
+            }
+            WatchRequestInner::Rewatch { token, path, done } => {
+                let _ = done.send(self.rewatch(inotify, token, path));
+            }
+            WatchRequestInner::Mute { token, muted, done } => {
+                let watcher = self.watcher_mut(token).filter(|watcher| !watcher.remove);
+                let found = watcher.is_some();
+                if let Some(watcher) = watcher {
+                    watcher.muted = muted;
+                }
+
+                let _ = done.send(found);
             }
             WatchRequestInner::Start {
                 path,
                 flags,
                 dir,
+                options,
                 sender,
+                end,
                 watch_token_tx,
             } => {
-                let watch = SingleWatch {
-                    flags,
-                    dir,
-                    remove: false,
-                    sender,
-                };
+                let watch = self.new_watcher(flags, dir, options, sender, end);
+
+                self.install(
+                    inotify,
+                    PendingStart {
+                        path,
+                        watch: Box::new(watch),
+                        attempts: 0,
+                        watch_token_tx,
+                    },
+                );
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Create a watcher with the next id, which has not been attached to a watch yet
+    fn new_watcher(
+        &mut self,
+        flags: AddWatchFlags,
+        dir: bool,
+        options: WatchOptions,
+        sender: Sender,
+        end: Arc<OnceLock<EndReason>>,
+    ) -> SingleWatch {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // Single event watches would resolve to a heartbeat, so never get them
+        let heartbeat_at = options
+            .heartbeat
+            .filter(|_| !matches!(sender, Sender::Once(_)))
+            .map(|interval| Instant::now() + interval);
+
+        SingleWatch {
+            id,
+            flags,
+            dir,
+            options,
+            remove: false,
+            sender,
+            heartbeat_at,
+            settling: Vec::new(),
+            end,
+            subscribers: 1,
+            muted: false,
+            written: HashSet::new(),
+            history: VecDeque::new(),
+            seq: 0,
+            delivered: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Attach a new watcher and report its token, or schedule another attempt if it hit a
+    /// system limit and retries are enabled
+    fn install(&mut self, inotify: &Inotify, mut pending: PendingStart) {
+        let id = pending.watch.id;
+        let expire = pending.watch.options.expire;
+        let heartbeat_at = pending.watch.heartbeat_at;
+
+        let wd = match self.attach(inotify, pending.path.clone(), *pending.watch) {
+            Ok(wd) => wd,
+            Err((e, watch)) => {
+                pending.watch = watch;
+
+                let retry = self.create_retry.filter(|retry| {
+                    pending.attempts < retry.attempts && ResourceLimit::from_errno(e).is_some()
+                });
+                if let Some(retry) = retry {
+                    let delay = retry
+                        .base_delay
+                        .saturating_mul(2u32.saturating_pow(pending.attempts));
+                    crate::debug!("Could not add watch, retrying in {delay:?}: {e}");
+
+                    pending.attempts += 1;
+                    self.retries.insert((Instant::now() + delay, id), pending);
+
+                    return;
+                }
+
+                crate::debug!("Could not add watch: {e}");
+                let _ = pending.watch_token_tx.send(Err(e));
+
+                return;
+            }
+        };
+
+        let token = WatchToken { wd, id };
+        if let Some(expire) = expire {
+            self.expiries.insert((Instant::now() + expire, id), token);
+        }
+        if let Some(at) = heartbeat_at {
+            self.heartbeats.insert((at, id), token);
+        }
+
+        let _ = pending.watch_token_tx.send(Ok(token));
+    }
+
+    /// Try installing every watcher whose retry is now due
+    fn retry(&mut self, inotify: &Inotify) {
+        let now = Instant::now();
+
+        while let Some(entry) = self.retries.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+
+            let pending = entry.remove();
+            // Nobody is waiting for it any more
+            if pending.watch_token_tx.is_closed() {
+                continue;
+            }
+
+            self.install(inotify, pending);
+        }
+    }
+
+    /// Add `watch` to the inotify watch for `path`, creating it if necessary
+    ///
+    /// Gives `watch` back if it could not be added.
+    fn attach(
+        &mut self,
+        inotify: &Inotify,
+        path: PathBuf,
+        watch: SingleWatch,
+    ) -> Result<WatchDescriptor, (Errno, Box<SingleWatch>)> {
+        let flags = watch.mask();
+        let watched = self.paths.contains_key(&path);
+
+        #[cfg(test)]
+        if !watched {
+            if let Some(e) = self.fail_creates.pop() {
+                return Err((e, Box::new(watch)));
+            }
+        }
+
+        // The path is added again even when it is already watched, as it may since have been
+        // replaced by another inode, with the IN_IGNORED for the old one not yet read
+        let create = if watch.options.exclusive && !watched {
+            flags | IN_MASK_CREATE
+        } else {
+            flags | IN_MASK_ADD
+        };
+
+        let wd = match inotify.add_watch(&path, create) {
+            Ok(wd) => wd,
+            Err(e) => return Err((e, Box::new(watch))),
+        };
+
+        if let Some(state) = self.watches.get_mut(&wd) {
+            if state.path != path {
+                trace!(
+                    "{} is the same inode as {}",
+                    path.display(),
+                    state.path.display()
+                );
+            }
+
+            state.mask |= flags;
+        } else {
+            let state = WatchState {
+                #[cfg(feature = "tracing")]
+                span: tracing_impl::debug_span!(
+                    "watch",
+                    path = %path.display(),
+                    wd = ?wd
+                ),
+                path: path.clone(),
+                mask: flags,
+                watchers: Vec::new(),
+            };
+
+            self.watches.insert(wd, state);
+            self.stats.note_watches(self.watches.len());
+        }
+
+        self.paths.insert(path, wd);
+
+        if matches!(watch.sender, Sender::Batch(_)) {
+            self.batches.insert(wd);
+        }
+        self.watches.get_mut(&wd).unwrap().watchers.push(watch);
 
-                if let Some(wd) = self.paths.get(&path) {
-                    let state = self.watches.get_mut(wd).unwrap();
-                    state.watchers.push(watch);
+        if let Err(e) = self.update_mask(inotify, wd) {
+            let watch = self.watches.get_mut(&wd).unwrap().watchers.pop().unwrap();
+            self.prune(inotify, wd);
 
-                    watch_token_tx.send(*wd);
+            return Err((e, Box::new(watch)));
+        }
+
+        Ok(wd)
+    }
+
+    /// Move the watcher for `token` onto `path`, returning its new token
+    ///
+    /// The new watch is added before the old one is removed, and no events are handled in
+    /// between, so none are missed.
+    fn rewatch(
+        &mut self,
+        inotify: &Inotify,
+        token: WatchToken,
+        path: PathBuf,
+    ) -> Result<Option<WatchToken>, Errno> {
+        let Some(state) = self.watches.get_mut(&token.wd) else {
+            return Ok(None);
+        };
+        let Some(index) = state
+            .watchers
+            .iter()
+            .position(|watcher| watcher.id == token.id && !watcher.remove)
+        else {
+            return Ok(None);
+        };
+
+        let watch = state.watchers.remove(index);
+        let wd = match self.attach(inotify, path, watch) {
+            Ok(wd) => wd,
+            Err((e, watch)) => {
+                // Leave the watcher on its old path
+                if let Some(state) = self.watches.get_mut(&token.wd) {
+                    state.watchers.push(*watch);
+                }
+
+                return Err(e);
+            }
+        };
+        let new_token = WatchToken { wd, id: token.id };
+
+        if wd != token.wd {
+            self.prune(inotify, token.wd);
+        }
+
+        for timer in self
+            .expiries
+            .values_mut()
+            .chain(self.heartbeats.values_mut())
+            .chain(self.settles.values_mut())
+        {
+            if *timer == token {
+                *timer = new_token;
+            }
+        }
+
+        Ok(Some(new_token))
+    }
+
+    /// Remove every watch on `prefix` or a path under it, returning how many watchers it had
+    ///
+    /// Paths are compared by component, so `/a/b` is not under `/a/bc`.
+    fn unwatch_prefix(&mut self, inotify: &Inotify, prefix: &Path) -> usize {
+        let wds = self
+            .watches
+            .iter()
+            .filter(|(_, state)| state.path.starts_with(prefix))
+            .map(|(wd, _)| *wd)
+            .collect::<Vec<_>>();
+
+        let mut removed = 0;
+        for wd in &wds {
+            let state = self.watches.get_mut(wd).unwrap();
+            trace!("Unwatching {}", state.path.display());
+
+            removed += state
+                .watchers
+                .drain(..)
+                .filter(|watcher| !watcher.remove)
+                .inspect(|watcher| {
+                    let _ = watcher.end.set(EndReason::Unwatched);
+                })
+                .count();
+        }
+
+        self.remove_watches(inotify, &wds);
+        self.retain_deadlines();
+
+        removed
+    }
+
+    /// Count what is still being watched, for a shutdown which asked for it
+    fn shutdown_report(&self) -> ShutdownReport {
+        ShutdownReport {
+            clean: true,
+            watches: self.watches.len(),
+            watchers: self
+                .watches
+                .values()
+                .flat_map(|state| &state.watchers)
+                .filter(|watcher| !watcher.remove)
+                .count(),
+        }
+    }
+
+    /// Forget the timers of watchers which have been removed
+    fn retain_deadlines(&mut self) {
+        self.expiries
+            .retain(|_, token| self.watches.contains_key(&token.wd));
+        self.heartbeats
+            .retain(|_, token| self.watches.contains_key(&token.wd));
+        self.settles
+            .retain(|_, token| self.watches.contains_key(&token.wd));
+    }
+
+    /// Drop the state for a watch which inotify has already removed
+    fn ignored(&mut self, wd: WatchDescriptor) {
+        // Watches we removed ourselves are already gone
+        let Some(state) = self.watches.remove(&wd) else {
+            return;
+        };
+
+        trace!("Watch for {} was removed", state.path.display());
+
+        // Unless it was already marked as unmounted
+        for watcher in &state.watchers {
+            let _ = watcher.end.set(EndReason::FileRemoved);
+        }
+
+        self.paths.retain(|_, path_wd| *path_wd != wd);
+        self.stats.note_watches(self.watches.len());
+        self.retain_deadlines();
+    }
+
+    fn snapshot(&self) -> RegistrySnapshot {
+        let watches = self
+            .watches
+            .values()
+            .map(|state| WatchSnapshot {
+                path: state.path.clone(),
+                mask: state.mask.bits(),
+                watchers: state
+                    .watchers
+                    .iter()
+                    .map(|watcher| WatcherSnapshot {
+                        id: WatchId(watcher.id),
+                        flags: watcher.flags.bits(),
+                        dir: watcher.dir,
+                        once: matches!(watcher.sender, Sender::Once(_)),
+                        closed: watcher.remove || watcher.sender.is_closed(),
+                        delivered: watcher.delivered,
+                        dropped: watcher.dropped,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        RegistrySnapshot { watches }
+    }
+
+    /// The next time an expiry, heartbeat, settle, or retry is due
+    fn next_deadline(&self) -> Option<Instant> {
+        [&self.expiries, &self.heartbeats, &self.settles]
+            .into_iter()
+            .filter_map(|timers| timers.keys().next().map(|(at, _)| *at))
+            .chain(self.retries.keys().next().map(|(at, _)| *at))
+            .min()
+    }
+
+    /// Deliver the held events for every entry which has now settled
+    fn settle(&mut self) {
+        let now = Instant::now();
+
+        while let Some(entry) = self.settles.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+
+            let token = entry.remove();
+            let Some(watch) = self.watches.get_mut(&token.wd) else {
+                continue;
+            };
+            let Some(watcher) = watch
+                .watchers
+                .iter_mut()
+                .find(|watcher| watcher.id == token.id && !watcher.remove)
+            else {
+                continue;
+            };
+
+            let (settled, settling) = std::mem::take(&mut watcher.settling)
+                .into_iter()
+                .partition::<Vec<_>, _>(|it| it.until <= now);
+            watcher.settling = settling;
+
+            for event in settled.into_iter().flat_map(|it| it.events) {
+                if deliver(
+                    watcher,
+                    &event,
+                    token.wd,
+                    &mut self.stats,
+                    &mut self.heartbeats,
+                ) {
+                    self.dirty = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Send a heartbeat to every watcher which has not had an event for its interval
+    fn heartbeat(&mut self) {
+        let now = Instant::now();
+
+        while let Some(entry) = self.heartbeats.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+
+            let token = entry.remove();
+            let Some(watch) = self.watches.get_mut(&token.wd) else {
+                continue;
+            };
+            let Some(watcher) = watch
+                .watchers
+                .iter_mut()
+                .find(|watcher| watcher.id == token.id && !watcher.remove)
+            else {
+                continue;
+            };
+            let Some(interval) = watcher.options.heartbeat else {
+                continue;
+            };
+
+            trace!("Sending heartbeat to watch {}", token.id);
+
+            let event = DirectoryWatchEvent {
+                inner_path: None,
+                event: FileWatchEvent::Heartbeat,
+                is_dir: false,
+                raw_mask: None,
+                watch_kind: if watcher.dir {
+                    WatchKind::Directory
                 } else {
-                    let wd = inotify.add_watch(&path, flags)?;
-                    let state = WatchState {
-                        path: path.clone(),
-                        watchers: Vec::from([watch]),
-                    };
+                    WatchKind::File
+                },
+                moved_to: None,
+                seq: None,
+                timestamp: self.timestamps.now(),
+            };
+
+            if watcher.send(&event, &mut self.stats) {
+                self.dirty = true;
+                continue;
+            }
+
+            let next = now + interval;
+            watcher.heartbeat_at = Some(next);
+            self.heartbeats.insert((next, token.id), token);
+        }
+    }
+
+    /// Send `error` to the watchers on `wd`, or on every watch, which asked for errors
+    fn report(&mut self, wd: Option<WatchDescriptor>, error: EventError) {
+        for (watch_wd, watch) in self.watches.iter_mut() {
+            if wd.is_some_and(|wd| wd != *watch_wd) {
+                continue;
+            }
+
+            for watcher in watch.watchers.iter_mut() {
+                // Single event watches would resolve to the error, so never get them
+                if !watcher.options.errors
+                    || watcher.remove
+                    || matches!(watcher.sender, Sender::Once(_))
+                {
+                    continue;
+                }
 
-                    self.paths.insert(path, wd);
-                    self.watches.insert(wd, state);
+                let event = DirectoryWatchEvent {
+                    inner_path: None,
+                    event: FileWatchEvent::Error(error),
+                    is_dir: false,
+                    raw_mask: None,
+                    watch_kind: if watcher.dir {
+                        WatchKind::Directory
+                    } else {
+                        WatchKind::File
+                    },
+                    moved_to: None,
+                    seq: None,
+                    timestamp: self.timestamps.now(),
+                };
 
-                    watch_token_tx.send(wd);
+                if watcher.send(&event, &mut self.stats) {
+                    self.dirty = true;
                 }
             }
+        }
+    }
+
+    /// Send every watcher a final error for `errno`, which stopped the task
+    ///
+    /// Unlike [`report`][`Watches::report`] this reaches every watcher, as it is the last thing
+    /// each of them will be sent.
+    fn fail(&mut self, errno: Errno) {
+        let timestamp = self.timestamps.now();
+
+        for watch in self.watches.values_mut() {
+            for watcher in watch.watchers.iter_mut().filter(|watcher| !watcher.remove) {
+                let _ = watcher.end.set(EndReason::Failed(errno));
+
+                let event = DirectoryWatchEvent {
+                    inner_path: None,
+                    event: FileWatchEvent::Error(EventError::Fatal(errno)),
+                    is_dir: false,
+                    raw_mask: None,
+                    watch_kind: if watcher.dir {
+                        WatchKind::Directory
+                    } else {
+                        WatchKind::File
+                    },
+                    moved_to: None,
+                    seq: None,
+                    timestamp,
+                };
+                watcher.send(&event, &mut self.stats);
+            }
+        }
+    }
+
+    /// Remove every watcher whose deadline has passed
+    fn expire(&mut self, inotify: &Inotify) {
+        let now = Instant::now();
+
+        while let Some(entry) = self.expiries.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+
+            let token = entry.remove();
+            trace!("Watch {} expired", token.id);
+            if let Some(watcher) = self.watcher_mut(token) {
+                let _ = watcher.end.set(EndReason::Expired);
+                // Even if other subscriptions are still using it
+                watcher.remove = true;
+            }
+            self.handle_drop(inotify, token);
+        }
+    }
+
+    /// Find a shared watcher on `path` with exactly this configuration, and subscribe to it
+    fn subscribe(
+        &mut self,
+        path: &Path,
+        flags: AddWatchFlags,
+        dir: bool,
+        options: &WatchOptions,
+    ) -> Option<Subscription> {
+        let wd = *self.paths.get(path)?;
+        let state = self.watches.get_mut(&wd)?;
+
+        state.watchers.iter_mut().find_map(|watcher| {
+            let Sender::Broadcast(ref sender) = watcher.sender else {
+                return None;
+            };
+            if watcher.remove
+                || watcher.dir != dir
+                || watcher.flags != flags
+                || watcher.options != *options
+            {
+                return None;
+            }
+
+            watcher.subscribers += 1;
+            let token = WatchToken { wd, id: watcher.id };
+            Some((token, sender.subscribe(), watcher.end.clone()))
+        })
+    }
+
+    fn watchers(&self, wd: WatchDescriptor) -> &[SingleWatch] {
+        self.watches
+            .get(&wd)
+            .map_or(&[], |state| state.watchers.as_slice())
+    }
+
+    fn watcher_mut(&mut self, token: WatchToken) -> Option<&mut SingleWatch> {
+        self.watches
+            .get_mut(&token.wd)?
+            .watchers
+            .iter_mut()
+            .find(|watcher| watcher.id == token.id)
+    }
+
+    /// Update the inotify watch on `wd` to cover exactly what its watchers are interested in
+    ///
+    /// Fails with `ESTALE`, leaving the watch as it was, if its path now refers to another inode
+    fn update_mask(&mut self, inotify: &Inotify, wd: WatchDescriptor) -> Result<(), Errno> {
+        let Some(state) = self.watches.get_mut(&wd) else {
+            return Ok(());
+        };
+
+        let mask = state
+            .watchers
+            .iter()
+            .filter(|watcher| !watcher.remove)
+            .fold(AddWatchFlags::empty(), |mask, watcher| {
+                mask | watcher.mask()
+            });
+
+        if mask.is_empty() || mask == state.mask {
+            return Ok(());
+        }
+
+        trace!("Updating mask for {} to {mask:?}", state.path.display());
+
+        let new_wd = inotify.add_watch(&state.path, mask)?;
+        if new_wd == wd {
+            state.mask = mask;
+            return Ok(());
+        }
+
+        // The path now refers to a different inode, so undo the change to whatever watch it hit
+        crate::debug!(
+            "{} no longer refers to the watched inode",
+            state.path.display()
+        );
+        match self.watches.get(&new_wd) {
+            Some(other) => {
+                inotify.add_watch(&other.path, other.mask)?;
+            }
+            None => {
+                inotify.rm_watch(new_wd)?;
+            }
+        }
+
+        Err(Errno::ESTALE)
+    }
+
+    /// Remove the dropped watcher, along with any others on the same watch which can no longer
+    /// receive events, and the inotify watch itself once no watchers remain
+    fn handle_drop(&mut self, inotify: &Inotify, token: WatchToken) {
+        let wd = token.wd;
+        let Some(state) = self.watches.get_mut(&wd) else {
+            return;
         };
 
-        Ok(())
+        if let Some(watcher) = state
+            .watchers
+            .iter_mut()
+            .find(|watcher| watcher.id == token.id && !watcher.remove && watcher.subscribers > 1)
+        {
+            // Another deduplicated subscription still uses this watcher
+            watcher.subscribers -= 1;
+            return;
+        }
+
+        state.watchers.retain(|watcher| {
+            watcher.id != token.id && !watcher.remove && !watcher.sender.is_closed()
+        });
+
+        self.prune(inotify, wd);
+    }
+
+    /// Narrow the inotify watch on `wd` to its remaining watchers, or remove it if there are none
+    fn prune(&mut self, inotify: &Inotify, wd: WatchDescriptor) {
+        let Some(state) = self.watches.get(&wd) else {
+            return;
+        };
+
+        if !state.watchers.is_empty() {
+            if let Err(e) = self.update_mask(inotify, wd) {
+                crate::debug!("Could not narrow watch: {e}");
+                self.report(Some(wd), EventError::Update(e));
+            }
+
+            return;
+        }
+
+        self.remove_watches(inotify, &[wd]);
+    }
+
+    /// Remove the inotify watches on `wds`, along with their state
+    ///
+    /// Takes many at once so that tearing down lots of watches only walks the path index once.
+    /// Watches are not removed one by one when the task exits, as dropping it closes the inotify
+    /// instance, which removes them all at once.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn remove_watches(&mut self, inotify: &Inotify, wds: &[WatchDescriptor]) {
+        let wds = wds
+            .iter()
+            .filter_map(|wd| Some((*wd, self.watches.remove(wd)?)))
+            .collect::<HashMap<_, _>>();
+
+        // Every link to the inode shares this watch
+        self.paths.retain(|_, path_wd| !wds.contains_key(path_wd));
+        self.stats.note_watches(self.watches.len());
+
+        for (wd, state) in wds {
+            trace!("Removing watch for {}", state.path.display());
+
+            // The kernel will have already removed the watch if the inode was deleted
+            if let Err(e) = inotify.rm_watch(wd) {
+                crate::debug!("Could not remove watch for {}: {e}", state.path.display());
+            }
+        }
     }
 }